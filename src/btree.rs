@@ -1,121 +1,592 @@
 use std::fs::{ File, OpenOptions };
-use std::io::{ Error, Seek, SeekFrom, Write };
+use std::io::{ Error, ErrorKind, Write };
+use std::path::Path;
+use std::ops::{ Bound, RangeBounds };
 use std::marker::PhantomData;
+use std::{ cmp, mem };
+use std::cmp::Ordering;
 use raw_serde::*;
+use type_tag::TypeTag;
 use file_buffer::*;
+use bulk::DEFAULT_FILL;
 use std::fmt::Debug;
-use node::{ Node, NodeCache };
+use node::{ Node, NodeCache, node_size, loc_slot, slot_loc, KEY_LOC_SIZE };
+use iter::{ Iter, Keys, Values, Range };
+use wal::{ Wal, TREE_FILE, KEY_FILE, VAL_FILE, CHECKPOINT_SIZE };
+use header::{ Header, HEADER_SIZE };
+use free_list::{ FreeLists, class_of, exact_size, record_size, MIN_RECORD, MIN_SIZED_BLOCK };
+use compact::{ self, Compaction };
+use inline_key::{ InlineKey, MAX_INLINE_KEY_SIZE };
+use key_cache::{ KeyCache, DEFAULT_KEY_CACHE_SIZE };
+use storage::{ Storage, FileStorage, Reader };
+use slab_policy::PolicyKind;
 
-pub const T: u64 = 16;
-pub const T_USIZE: usize = T as usize;
-pub const NUM_CHILDREN: usize = (T * 2) as usize;
-pub const NUM_KEYS: usize = NUM_CHILDREN - 1;
+/// The minimum degree t of trees created by PBTree::new. Every node but the root holds between
+/// t - 1 and 2t - 1 keys.
+pub const DEFAULT_DEGREE: u64 = 16;
+/// The smallest minimum degree a tree can have.
+pub const MIN_DEGREE: u64 = 2;
+/// The largest minimum degree a tree can have.
+pub const MAX_DEGREE: u64 = 1 << 16;
 
 pub const NONE: u64 = 0xFFFFFFFFFFFFFFFFu64;
 
-pub struct PBTree<K, V> {
-    pub treefile: BufFile,
-    pub keyfile: BufFile,
-    pub valfile: BufFile,
+/// How many blocks of a free list are looked at before giving up on finding one large enough.
+const FREE_SEARCH: usize = 16;
+
+/// The number of bytes save_to copies at a time.
+const SAVE_CHUNK: usize = 1 << 20;
+
+/// What is needed to undo the in-memory effects of an operation that failed part way.
+struct Rollback {
+    /// The bytes each write of the operation overwrote, as (file id, position, old bytes).
+    writes: Vec<(u8, u64, Vec<u8>)>,
+    /// The ends of the tree, key and value files when the operation started.
+    ends: [u64; 3],
     root_location: u64,
+    root: Node,
+    len: u64,
+    free: FreeLists
+}
+
+/// How a tree is created or opened by the constructors that take options. Start from
+/// `Options::default()` and change what is needed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Options {
+    /// The minimum degree of a new tree. A tree that is opened keeps the degree it was created
+    /// with.
+    pub degree: u64,
+    /// The fraction of each node a bulk load fills, as for `PBTree::bulk_load_with`.
+    pub fill: f64,
+    /// The size of the slabs the files of a tree are read and written in, if they are BufFiles,
+    /// which must be a power of two. Small slabs, such as 4 KiB pages, suit trees that are
+    /// searched at random, since a miss reads only one slab; large slabs suit bulk loads and
+    /// scans. Other storage ignores it.
+    pub slab_size: usize,
+    /// The number of slabs each BufFile keeps in memory.
+    pub slabs: usize,
+    /// How each BufFile chooses which slab to evict when it needs room for another. LFU keeps
+    /// the slabs used most, while LRU, CLOCK and ARC adapt sooner when the part of the tree in
+    /// use moves.
+    pub policy: PolicyKind
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            degree: DEFAULT_DEGREE,
+            fill: DEFAULT_FILL,
+            slab_size: DEFAULT_SLAB_SIZE,
+            slabs: DEFAULT_NUM_SLABS,
+            policy: PolicyKind::Lfu
+        }
+    }
+}
+
+/// A summary of the shape and size of a tree, as returned by PBTree::stats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of entries in the tree.
+    pub len: u64,
+    /// The number of levels of nodes, counting the root. A tree with only a root has height 1.
+    pub height: u64,
+    /// The number of nodes in the tree, internal and leaf.
+    pub nodes: u64,
+    /// The number of leaf nodes in the tree.
+    pub leaves: u64,
+    /// The minimum degree of the tree.
+    pub degree: u64,
+    /// The lengths of the tree, key and value files in bytes.
+    pub tree_bytes: u64,
+    pub key_bytes: u64,
+    pub val_bytes: u64
+}
+
+/// A B-tree whose nodes, keys and values are kept in three stores of type S, which are files at
+/// a path unless the tree is given other storage.
+pub struct PBTree<K, V, S = BufFile> {
+    pub treefile: S,
+    pub keyfile: S,
+    pub valfile: S,
+    pub(crate) root_location: u64,
     pub root: Node,
+    /// The minimum degree of the tree.
+    t: u64,
+    /// Whether keys are kept in the nodes rather than the key file.
+    inline_keys: bool,
+    /// The size of the key slots of the nodes.
+    key_size: u64,
+    /// The number of entries in the tree.
+    len: u64,
+    /// The free space in the tree, key and value files.
+    free: FreeLists,
+    /// For the key and value files, the number of blocks freed since their free lists were last
+    /// merged and the number of blocks the merge left, which decide when to merge them again.
+    freed_since_merge: [u64; 2],
+    blocks_after_merge: [u64; 2],
     node_cache: NodeCache,
+    key_cache: KeyCache<K>,
+    wal: Wal,
+    rollback: Rollback,
+    /// The path the tree was opened at, without an extension. None if the tree was given its
+    /// storage.
+    pub(crate) path: Option<String>,
+    /// The compaction in progress, if compact_step has been called but has not finished.
+    pub(crate) compaction: Option<Compaction<K, S>>,
+    /// The options the tree was created or opened with, which its files are opened with again
+    /// when they are replaced.
+    pub(crate) options: Options,
+    /// Set when a compaction failed after it began replacing the files of the tree, whose
+    /// handles may then refer to files that are no longer at its path. Nothing more can be done
+    /// with the tree; opening it again finishes the compaction.
+    pub(crate) poisoned: bool,
+    /// While set, operations leave their writes pinned and skip the checkpoint, so a
+    /// SharedPBTree can choose when they become visible to searches.
+    pub(crate) hold_writes: bool,
     phantom_k: PhantomData<K>,
     phantom_v: PhantomData<V>
 }
 
-impl<K, V> PBTree<K, V>
-    where   K: RawSerialize + RawDeserialize + Eq + Ord + Debug,
-            V: RawSerialize + RawDeserialize + Debug {
+impl<K, V, S: FileStorage> PBTree<K, V, S>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
+
+    /// Creates a new, empty tree with the default minimum degree, truncating any files already
+    /// at the path.
+    pub fn new<P: Into<String>>(_path: P) -> Result<Self, Error> {
+        Self::with_degree(_path, DEFAULT_DEGREE)
+    }
+
+    /// Creates a new, empty tree whose nodes hold between t - 1 and 2t - 1 keys. Wide nodes
+    /// make for shallow trees, which suits small keys; narrow nodes keep the treefile small.
+    /// Fails if t is less than MIN_DEGREE or greater than MAX_DEGREE.
+    pub fn with_degree<P: Into<String>>(_path: P, t: u64) -> Result<Self, Error> {
+        Self::with_options(_path, Options { degree: t, ..Options::default() })
+    }
+
+    /// Creates a new, empty tree with the given options, truncating any files already at the
+    /// path. Fails with ErrorKind::InvalidInput if any of the options is out of range.
+    pub fn with_options<P: Into<String>>(_path: P, options: Options) -> Result<Self, Error> {
+        Self::create(_path.into(), options, 0)
+    }
+
+    /// Creates a new, empty tree of minimum degree t that keeps its keys in its nodes instead of
+    /// the key file. Looking a key up then reads one node per level and nothing else, but every
+    /// key takes up space in the nodes whether it is in use or not, so this suits small keys.
+    pub fn with_inline_keys<P: Into<String>>(_path: P, t: u64) -> Result<Self, Error> where K: InlineKey {
+        if K::SIZE == 0 || K::SIZE > MAX_INLINE_KEY_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                format!("inline keys must be between 1 and {} bytes, not {}", MAX_INLINE_KEY_SIZE, K::SIZE)));
+        }
+        Self::create(_path.into(), Options { degree: t, ..Options::default() }, K::SIZE)
+    }
+
+    /// Creates a new tree with the given options, with inline keys of the given size unless it
+    /// is 0.
+    fn create(path: String, options: Options, inline_key_size: u64) -> Result<Self, Error> {
+        check!(check_degree(options.degree));
+        check!(check_slabs(options.slab_size, options.slabs));
+
+        // A compaction left over from the tree that was here must not be swapped in over this one
+        check!(compact::discard(&path));
+
+        let files;
+        check!(S::open_files(&path, true, &options), files);
+        let (treefile, keyfile, valfile) = files;
+
+        let _walfile;
+        check!(OpenOptions::new().read(true).write(true).truncate(true).create(true).open(path.clone() + ".wal"), _walfile);
+        let wal;
+        check!(Wal::new(_walfile), wal);
+
+        let mut tree;
+        check!(Self::init(treefile, keyfile, valfile, wal, Some(path), options.degree, inline_key_size), tree);
+        tree.options = options;
+        Ok(tree)
+    }
+
+    /// Opens a tree previously created with `PBTree::new`, preserving its contents. Operations
+    /// committed to the write-ahead log are replayed first. Fails if any of the three files is
+    /// missing, if the header does not describe a tree of this format version with keys of type K
+    /// and values of type V, or if the root pointer and root node are inconsistent with the files
+    /// they live in. A compaction that was interrupted while being swapped in is completed
+    /// first, and the files of any other unfinished compaction are deleted.
+    pub fn open<P: Into<String>>(_path: P) -> Result<Self, Error> {
+        Self::open_with_options(_path, Options::default())
+    }
 
-    pub fn new<S: Into<String>>(_path: S) -> Result<Self, Error> {
+    /// Opens a tree as `PBTree::open` does, with the given options. The degree and fill are
+    /// ignored, since the tree keeps the degree it was created with.
+    pub fn open_with_options<P: Into<String>>(_path: P, options: Options) -> Result<Self, Error> {
         let path = _path.into();
+        check!(check_slabs(options.slab_size, options.slabs));
+        check!(compact::recover(&path));
 
-        let mut _treefile;
-        check!(OpenOptions::new().read(true).write(true).truncate(true).create(true).open(path.clone() + ".tree"), _treefile);
-        let mut treefile;
-        check!(BufFile::new(_treefile), treefile);
-
-        let _keyfile;
-        check!(OpenOptions::new().read(true).write(true).truncate(true).create(true).open(path.clone() + ".key"), _keyfile);
-        let mut keyfile;
-        check!(BufFile::new(_keyfile), keyfile);
-
-        let _valfile;
-        check!(OpenOptions::new().read(true).write(true).truncate(true).create(true).open(path.clone() + ".val"), _valfile);
-        let mut valfile;
-        check!(BufFile::new(_valfile), valfile);
-
-        let mut root = Node::new();
-        root.loc = 8;
-        check!(treefile.seek(SeekFrom::Start(0)));
-        // Location of root is written at the first 8 bytes of the treefile
-        check!(8u64.raw_serialize(&mut treefile));
-        // Write the first node, at the second 8 bytes of the treefile
-        check!(root.raw_serialize(&mut treefile));
+        let files;
+        check!(S::open_files(&path, false, &options), files);
+        let (treefile, keyfile, valfile) = files;
+        let mut files = vec![treefile, keyfile, valfile];
 
-        Ok(PBTree {
-            keyfile,
-            valfile,
-            treefile,
-            root_location: 8,
-            root,
-            node_cache: NodeCache::new(128),
-            phantom_k: PhantomData {},
-            phantom_v: PhantomData {}
-        })
+        let _walfile;
+        check!(OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path.clone() + ".wal"), _walfile);
+        let mut wal;
+        check!(Wal::new(_walfile), wal);
+        check!(wal.replay(&mut files));
+
+        let mut files = files.into_iter();
+        let mut tree;
+        check!(Self::load(files.next().unwrap(), files.next().unwrap(), files.next().unwrap(), wal, Some(path)), tree);
+        tree.options = options;
+        Ok(tree)
     }
 
-    pub fn set_cache_size(&mut self, size: usize) {
-        self.node_cache.size = size;
+    /// Opens the tree again from its files, e.g. after they have been replaced. Everything must
+    /// have been synced.
+    pub(crate) fn reopen(&mut self) -> Result<(), Error> {
+        let cache_size = self.node_cache.size;
+        let key_cache_size = self.key_cache.limit();
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Err(Error::other("a tree that was given its storage can't be reopened"))
+        };
+        let tree;
+        check!(Self::open_with_options(path, self.options), tree);
+        *self = tree;
+        self.node_cache.size = cache_size;
+        self.key_cache.set_limit(key_cache_size);
+        Ok(())
     }
 
-    pub fn open<S: Into<String>>(_path: S) -> Result<Self, Error> {
+    /// Opens the tree at the given path if its treefile exists, otherwise creates a new one.
+    pub fn open_or_create<P: Into<String>>(_path: P) -> Result<Self, Error> {
+        Self::open_or_create_with_options(_path, Options::default())
+    }
+
+    /// Opens the tree at the given path with the given options if its treefile exists, otherwise
+    /// creates a new one with them.
+    pub fn open_or_create_with_options<P: Into<String>>(_path: P, options: Options) -> Result<Self, Error> {
         let path = _path.into();
+        if Path::new(&(path.clone() + S::EXTENSIONS[0])).exists() {
+            Self::open_with_options(path, options)
+        } else {
+            Self::with_options(path, options)
+        }
+    }
+}
 
-        let mut _treefile;
-        check!(OpenOptions::new().read(true).write(true).truncate(true).create(true).open(path.clone() + ".tree"), _treefile);
-        let mut treefile;
-        check!(BufFile::new(_treefile), treefile);
+impl<K, V> PBTree<K, V, Vec<u8>>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
 
-        let _keyfile;
-        check!(OpenOptions::new().read(true).write(true).truncate(true).create(true).open(path.clone() + ".key"), _keyfile);
-        let mut keyfile;
-        check!(BufFile::new(_keyfile), keyfile);
+    /// Creates a new, empty tree with the default minimum degree that is kept entirely in
+    /// memory and never touches the disk. It behaves exactly like a tree kept in files, and can
+    /// be written to files with save_to.
+    pub fn in_memory() -> Result<Self, Error> {
+        Self::with_storage(vec![], vec![], vec![], DEFAULT_DEGREE)
+    }
+}
 
-        let _valfile;
-        check!(OpenOptions::new().read(true).write(true).truncate(true).create(true).open(path.clone() + ".val"), _valfile);
-        let mut valfile;
-        check!(BufFile::new(_valfile), valfile);
+impl<K, V, S: Storage> PBTree<K, V, S>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
 
-        let root_location;
-        check!(u64::raw_deserialize(&mut treefile), root_location);
+    /// Creates a new, empty tree of minimum degree t in the given storage: one store for the
+    /// nodes, one for the keys and one for the values, whatever they held before is overwritten.
+    /// A tree that was given its storage runs without a write-ahead log, so it is only as
+    /// durable as its storage, and it can't be compacted.
+    pub fn with_storage(treefile: S, keyfile: S, valfile: S, t: u64) -> Result<Self, Error> {
+        Self::init(treefile, keyfile, valfile, Wal::disabled(), None, t, 0)
+    }
+
+    /// Opens a tree that was created in the given storage, as for `PBTree::open`.
+    pub fn open_storage(treefile: S, keyfile: S, valfile: S) -> Result<Self, Error> {
+        Self::load(treefile, keyfile, valfile, Wal::disabled(), None)
+    }
+
+    /// Creates a new tree of minimum degree t in the given storage, with inline keys of the
+    /// given size unless it is 0.
+    fn init(mut treefile: S, mut keyfile: S, mut valfile: S, wal: Wal, path: Option<String>, t: u64, inline_key_size: u64)
+        -> Result<Self, Error> {
+        check!(check_degree(t));
+        check!(treefile.truncate(0));
+        check!(keyfile.truncate(0));
+        check!(valfile.truncate(0));
+
+        let key_size = if inline_key_size == 0 { KEY_LOC_SIZE } else { inline_key_size };
+        let mut root = Node::new(t, key_size);
+        root.loc = HEADER_SIZE;
+        // The header takes up the start of the treefile
+        check!(treefile.write_at(0, &Header::new::<K, V>(HEADER_SIZE, t, inline_key_size, 0, FreeLists::new()).to_bytes()));
+        // Write the first node right after
+        let mut buf = vec![];
+        check!(root.raw_serialize(&mut buf));
+        check!(treefile.write_at(HEADER_SIZE, &buf));
+
+        let mut tree = PBTree {
+            keyfile,
+            valfile,
+            treefile,
+            root_location: HEADER_SIZE,
+            root: root.clone(),
+            t,
+            inline_keys: inline_key_size != 0,
+            key_size,
+            len: 0,
+            free: FreeLists::new(),
+            node_cache: NodeCache::new(128, t, key_size),
+            key_cache: KeyCache::new(DEFAULT_KEY_CACHE_SIZE),
+            wal,
+            rollback: Rollback { writes: vec![], ends: [0; 3], root_location: HEADER_SIZE, root, len: 0, free: FreeLists::new() },
+            path,
+            compaction: None,
+            options: Options::default(),
+            poisoned: false,
+            hold_writes: false,
+            freed_since_merge: [0; 2],
+            blocks_after_merge: [0; 2],
+            phantom_k: PhantomData {},
+            phantom_v: PhantomData {}
+        };
+        check!(tree.sync());
+        Ok(tree)
+    }
+
+    /// Loads a tree from its storage, whose write-ahead log has been replayed, making sure the
+    /// header and the root node are consistent with the storage they live in.
+    fn load(mut treefile: S, keyfile: S, valfile: S, wal: Wal, path: Option<String>) -> Result<Self, Error> {
+        let name = match path {
+            Some(ref path) => path.clone(),
+            None => "<storage>".to_string()
+        };
+        if treefile.len() < HEADER_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{}.tree is too short ({} bytes) to contain a tree", name, treefile.len())));
+        }
+
+        let header;
+        check!(Header::read::<K, V>(&mut Reader::new(&mut treefile, 0), &name), header);
+        let Header { root_location, degree: t, inline_key_size, len, free, .. } = header;
+        let key_size = if inline_key_size == 0 { KEY_LOC_SIZE } else { inline_key_size };
+        let node_size = node_size(t, key_size);
+        if treefile.len() < HEADER_SIZE + node_size {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{}.tree is too short ({} bytes) to contain a tree", name, treefile.len())));
+        }
+        if root_location < HEADER_SIZE || root_location + node_size > treefile.len() {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("root location {} lies outside of {}.tree", root_location, name)));
+        }
+        let heads_valid = (free.nodes == NONE || (free.nodes >= HEADER_SIZE && free.nodes + node_size <= treefile.len()))
+            && free.keys.iter().all(|&head| head == NONE || head + MIN_RECORD <= keyfile.len())
+            && free.values.iter().all(|&head| head == NONE || head + MIN_RECORD <= valfile.len());
+        if !heads_valid {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("the header of {}.tree references free space past the end of a file", name)));
+        }
 
-        check!(treefile.seek(SeekFrom::Start(root_location)));
         let root;
-        check!(Node::raw_deserialize(&mut treefile), root);
+        check!(Node::read_at(&mut treefile, root_location, t, key_size), root);
+        if root.loc != root_location || root.len > 2 * t - 1 {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("root node at {} in {}.tree is corrupt", root_location, name)));
+        }
+        for i in 0..root.len as usize {
+            if (inline_key_size == 0 && root.key_loc(i) >= keyfile.len()) || root.values[i] >= valfile.len() {
+                return Err(Error::new(ErrorKind::InvalidData,
+                    format!("root node references an entry past the end of {}.key or {}.val", name, name)));
+            }
+        }
+        if !root.leaf {
+            for i in 0..root.len as usize + 1 {
+                if root.children[i] + node_size > treefile.len() {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                        format!("root node references a child past the end of {}.tree", name)));
+                }
+            }
+        }
 
         Ok(PBTree {
             keyfile,
             valfile,
             treefile,
             root_location,
-            node_cache: NodeCache::new(20),
-            root,
+            t,
+            inline_keys: inline_key_size != 0,
+            key_size,
+            len,
+            free: free.clone(),
+            node_cache: NodeCache::new(20, t, key_size),
+            key_cache: KeyCache::new(DEFAULT_KEY_CACHE_SIZE),
+            root: root.clone(),
+            wal,
+            rollback: Rollback { writes: vec![], ends: [0; 3], root_location, root, len, free },
+            path,
+            compaction: None,
+            options: Options::default(),
+            poisoned: false,
+            hold_writes: false,
+            freed_since_merge: [0; 2],
+            blocks_after_merge: [0; 2],
             phantom_k: PhantomData {},
             phantom_v: PhantomData {}
         })
     }
 
-    pub fn flush(&mut self) -> Result<(), Error> {
-        check!(self.keyfile.flush());
-        check!(self.valfile.flush());
-        check!(self.treefile.flush());
+    /// Sets how many nodes are kept in memory. 0 turns the cache off; a smaller size takes
+    /// effect as nodes are next read.
+    pub fn set_cache_size(&mut self, size: usize) {
+        self.node_cache.size = size;
+    }
+
+    /// Sets how many bytes of deserialized keys are kept in memory to be compared against,
+    /// DEFAULT_KEY_CACHE_SIZE to begin with. 0 turns the cache off. Trees with inline keys
+    /// never use it.
+    pub fn set_key_cache_size(&mut self, bytes: usize) {
+        self.key_cache.set_limit(bytes);
+    }
+
+    /// Writes everything buffered to the three files and waits for it to reach the disk, after
+    /// which the write-ahead log is no longer needed and is emptied. Keys and values become
+    /// durable before the nodes that reference them, and the header last, so a power loss
+    /// part way through never leaves a node pointing at unwritten data.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        check!(self.check_usable());
+        check!(self.keyfile.sync());
+        check!(self.valfile.sync());
+        let end = self.treefile.len();
+        check!(self.treefile.sync_range(HEADER_SIZE, end));
+        check!(self.treefile.sync_range(0, HEADER_SIZE));
+        self.wal.truncate()
+    }
+
+    /// Writes a copy of the tree to files at the path, truncating any already there, which can
+    /// then be opened with `PBTree::open`. This is how a tree kept in memory is persisted. Fails
+    /// with ErrorKind::InvalidInput if the path is the one the tree itself was opened at.
+    pub fn save_to<P: Into<String>>(&mut self, _path: P) -> Result<(), Error> {
+        check!(self.check_usable());
+        let path = _path.into();
+        if self.path.as_ref() == Some(&path) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("can't save the tree at {} over its own files", path)));
+        }
+        check!(compact::discard(&path));
+        for &(ext, file_id) in [(".key", KEY_FILE), (".val", VAL_FILE), (".tree", TREE_FILE)].iter() {
+            let mut file;
+            check!(OpenOptions::new().write(true).truncate(true).create(true).open(path.clone() + ext), file);
+            check!(self.copy_file(file_id, &mut file));
+            check!(file.sync_data());
+        }
+        // Any write-ahead log left at the path must not be replayed over the copy
+        check!(OpenOptions::new().write(true).truncate(true).create(true).open(path + ".wal"));
         Ok(())
     }
 
-    pub fn keys(&mut self) -> Result<Vec<K>, Error> {
-        panic!("Do you really want to do that?")
+    /// Copies the whole of the tree, key or value file to out.
+    fn copy_file(&mut self, file_id: u8, out: &mut File) -> Result<(), Error> {
+        let len = self.file(file_id).len();
+        let mut buf = vec![0u8; SAVE_CHUNK];
+        let mut pos = 0;
+        while pos < len {
+            let n = cmp::min(len - pos, SAVE_CHUNK as u64) as usize;
+            check!(self.file(file_id).read_at(pos, &mut buf[.. n]));
+            check!(out.write_all(&buf[.. n]));
+            pos += n as u64;
+        }
+        Ok(())
+    }
+
+    /// The minimum degree of the tree.
+    pub fn degree(&self) -> u64 {
+        self.t
+    }
+
+    /// The number of entries in the tree. This is kept in the header, so it is never counted.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the tree has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Walks every node of the tree to gather statistics about it. Keys and values are not read,
+    /// but this still takes time proportional to the number of nodes.
+    pub fn stats(&mut self) -> Result<Stats, Error> {
+        check!(self.check_usable());
+        let mut stats = Stats {
+            len: self.len,
+            height: 0,
+            nodes: 0,
+            leaves: 0,
+            degree: self.t,
+            tree_bytes: self.treefile.len(),
+            key_bytes: self.keyfile.len(),
+            val_bytes: self.valfile.len()
+        };
+        // Every leaf is at the same depth, so the leftmost path gives the height
+        let mut loc = self.root_location;
+        loop {
+            let node;
+            check!(self.read_node(loc), node);
+            stats.height += 1;
+            if node.leaf { break }
+            loc = node.children[0];
+        }
+
+        let mut stack = vec![self.root_location];
+        while let Some(loc) = stack.pop() {
+            let node;
+            check!(self.read_node(loc), node);
+            stats.nodes += 1;
+            if node.leaf {
+                stats.leaves += 1;
+            } else {
+                stack.extend_from_slice(&node.children[0 .. node.len as usize + 1]);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// The most keys a node can hold.
+    #[inline(always)]
+    fn max_keys(&self) -> u64 {
+        2 * self.t - 1
+    }
+
+    /// The same as sync.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.sync()
+    }
+
+    /// Iterates over every entry in the tree in key order, reading nodes lazily.
+    pub fn iter(&mut self) -> Iter<'_, K, V, S> {
+        Iter::new(self)
+    }
+
+    /// Iterates over every key in the tree in order, reading nodes lazily.
+    pub fn keys(&mut self) -> Keys<'_, K, V, S> {
+        Keys::new(self)
+    }
+
+    /// Iterates over every value in the tree in the order of their keys, reading nodes lazily.
+    pub fn values(&mut self) -> Values<'_, K, V, S> {
+        Values::new(self)
+    }
+
+    /// Iterates over the entries whose keys lie within the range, in key order. The range may
+    /// also be walked backwards.
+    pub fn range<R: RangeBounds<K>>(&mut self, r: R) -> Range<'_, K, V, S> where K: Clone {
+        let start = match r.start_bound() {
+            Bound::Included(k) => Bound::Included(k.clone()),
+            Bound::Excluded(k) => Bound::Excluded(k.clone()),
+            Bound::Unbounded => Bound::Unbounded
+        };
+        let end = match r.end_bound() {
+            Bound::Included(k) => Bound::Included(k.clone()),
+            Bound::Excluded(k) => Bound::Excluded(k.clone()),
+            Bound::Unbounded => Bound::Unbounded
+        };
+        Range::new(self, start, end)
     }
 
     fn split_child(&mut self, x: &mut Node, child: usize) -> Result<(), Error> {
@@ -124,118 +595,232 @@ impl<K, V> PBTree<K, V>
 
         y.parent = x.loc;
 
-        let mut z = Node::new();
+        let t = self.t as usize;
+        let mut z = Node::new(self.t, self.key_size);
         z.leaf = y.leaf;
-        z.len = T - 1;
+        z.len = self.t - 1;
 
-        for j in 0 .. T_USIZE - 1 { z.keys[j] = y.keys[j + T_USIZE]; z.values[j] = y.values[j + T_USIZE]; }
+        z.copy_entries(0, &y, t, t - 1);
 
-        if !y.leaf { for j in 0..T_USIZE { z.children[j] = y.children[j + T_USIZE]; } }
+        if !y.leaf { for j in 0..t { z.children[j] = y.children[j + t]; } }
 
-        y.len = T - 1;
+        y.len = self.t - 1;
 
-        for j in ((child + 1) as usize .. (x.len + 1) as usize).rev() { x.children[j + 1] = x.children[j]; }
+        for j in (child + 1 .. (x.len + 1) as usize).rev() { x.children[j + 1] = x.children[j]; }
 
         let z_loc;
         z.parent = x.loc;
         check!(self.write_node(&mut z), z_loc);
         x.children[child + 1] = z_loc;
 
-        for j in (child as u64 .. x.len).rev() { x.keys[j as usize + 1] = x.keys[j as usize]; x.values[j as usize + 1] = x.values[j as usize]; }
+        let moved = x.len as usize - child;
+        x.move_entries(child, child + 1, moved);
         x.len += 1;
-        x.keys[child] = y.keys[T_USIZE - 1];
-        x.values[child] = y.values[T_USIZE - 1];
+        x.copy_entries(child, &y, t - 1, 1);
 
-        check!(self.update_node(&x));
+        check!(self.update_node(x));
         check!(self.update_node(&y));
 
         Ok(())
     }
 
-    pub fn insert(&mut self, k: &K, v: &V) -> Result<(), Error> {
-        if self.root.len == NUM_KEYS as u64 {
-            let mut s = Node::new();
-            let s_loc;
-            s.leaf = false;
-            s.len = 0;
-            s.children[0] = self.root_location;
+    /// Inserts the pair (k, v). If k is already present its value is replaced, and the previous
+    /// value is returned.
+    pub fn insert(&mut self, k: &K, v: &V) -> Result<Option<V>, Error> {
+        check!(self.begin());
+        let res = self.insert_root(k, v, true);
+        if let Ok(None) = res { self.len += 1; }
+        if res.is_ok() { self.note_changed(k); }
+        self.finish(res)
+    }
 
-            check!(self.write_node(&mut s), s_loc);
-            self.root_location = s_loc;
-            s.loc = s_loc;
+    /// Inserts the pair (k, v), failing with ErrorKind::AlreadyExists if k is already present.
+    pub fn insert_unique(&mut self, k: &K, v: &V) -> Result<(), Error> {
+        check!(self.begin());
+        let res = self.insert_root(k, v, false);
+        if res.is_ok() { self.len += 1; self.note_changed(k); }
+        match self.finish(res) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e)
+        }
+    }
 
-            check!(self.treefile.seek(SeekFrom::Start(0)));
-            check!(self.root_location.raw_serialize(&mut self.treefile));
+    /// Inserts every entry of the batch, replacing the values of keys already present, and
+    /// returns how many of the keys were not. If a key appears more than once, the last of its
+    /// entries wins. The batch is sorted first so that the tree can be descended once for each
+    /// run of keys that land in the same leaf, rather than once per key. It is applied
+    /// atomically: if any of it fails, none of it is applied.
+    pub fn insert_batch<I: IntoIterator<Item=(K, V)>>(&mut self, entries: I) -> Result<u64, Error> {
+        let mut batch: Vec<(K, V)> = entries.into_iter().collect();
+        // The sort is stable, so the last of the entries with equal keys comes first in reverse
+        batch.sort_by(|a, b| a.0.cmp(&b.0));
+        batch.reverse();
+        batch.dedup_by(|a, b| a.0 == b.0);
+        batch.reverse();
 
-            check!(self.split_child(&mut s, 0));
-            check!(self.insert_nonfull(&mut s, k, v));
+        check!(self.begin());
+        let res = self.insert_batch_root(&batch);
+        if let Ok(added) = res {
+            self.len += added;
+            for (k, _) in batch.iter() { self.note_changed(k); }
+        }
+        self.finish(res)
+    }
 
-            self.root = s.clone();
-            check!(self.update_node(&s));
-        } else {
+    fn insert_batch_root(&mut self, batch: &[(K, V)]) -> Result<u64, Error> {
+        let mut added = 0;
+        let mut done = 0;
+        while done < batch.len() {
+            if self.root.len == self.max_keys() {
+                check!(self.grow_root());
+            }
             let mut root = self.root.clone();
-            check!(self.insert_nonfull(&mut root, k, v));
+            let res = self.insert_group(&mut root, &batch[done ..]);
             self.root = root;
+            let inserted;
+            check!(res, inserted);
+            let (consumed, new) = inserted;
+            done += consumed;
+            added += new;
         }
-        Ok(())
+        Ok(added)
     }
 
-    fn insert_nonfull(&mut self, x: &mut Node, k: &K, v: &V) -> Result<(), Error> {
-        let mut i = x.len as i64;
-        if x.leaf {
-            if i > 0 {
-                i -= 1;
-                let mut k_i;
-                check!(self.read_key(x.keys[i as usize]), k_i);
-                while i >= 0 && *k < k_i {
-                    x.keys[i as usize + 1] = x.keys[i as usize];
-                    x.values[i as usize + 1] = x.values[i as usize];
-                    i -= 1;
-                    if i >= 0 { check!(self.read_key(x.keys[i as usize]), k_i); }
-                }
-                i += 1;
-                let entry_loc;
-                check!(self.write_entry(k, v), entry_loc);
-                let (k_loc, v_loc) = entry_loc;
-                x.keys[i as usize] = k_loc;
-                x.values[i as usize] = v_loc;
-                x.len += 1;
-                check!(self.update_node(&*x));
-                Ok(())
-            } else {
-                let entry_loc;
-                check!(self.write_entry(k, v), entry_loc);
-                let (k_loc, v_loc) = entry_loc;
-                x.keys[0] = k_loc;
-                x.values[0] = v_loc;
+    /// Inserts a sorted run of entries into the subtree rooted at x, which must not be full,
+    /// for as long as x has room for the keys its children push up when they split. Returns how
+    /// many of the entries were inserted, and how many of those were not already present.
+    fn insert_group(&mut self, x: &mut Node, entries: &[(K, V)]) -> Result<(usize, u64), Error> {
+        let mut done = 0;
+        let mut added = 0;
+        while done < entries.len() {
+            let (ref k, ref v) = entries[done];
+            let found;
+            check!(self.locate(x, k), found);
+            let (i, present) = found;
+            if present {
+                check!(self.replace_value(x, i, v, true));
+                done += 1;
+                continue
+            }
+
+            if x.leaf {
+                if x.len == self.max_keys() { break }
+                let moved = x.len as usize - i;
+                x.move_entries(i, i + 1, moved);
+                let entry;
+                check!(self.write_entry(k, v), entry);
+                x.set_entry(i, &entry.0, entry.1);
                 x.len += 1;
-                check!(self.update_node(&*x));
-                Ok(())
+                done += 1;
+                added += 1;
+                continue
             }
-        } else {
-            let mut k_i;
-            i -= 1;
-            check!(self.read_key(x.keys[i as usize]), k_i);
-            while i >= 0 && *k < k_i {
-                i -= 1;
-                if i >= 0 { check!(self.read_key(x.keys[i as usize]), k_i); }
-                else { break }
+
+            let mut c;
+            check!(self.node(x.children[i]), c);
+            if c.len == self.max_keys() {
+                if x.len == self.max_keys() { break }
+                check!(self.split_child(x, i));
+                // Look again, as the entry may belong on the other side of the key pushed up
+                continue
             }
-            i += 1;
+            // The entries that belong in child i are those before key i of x
+            let end = if i < x.len as usize {
+                let k_i;
+                check!(self.node_key(x, i), k_i);
+                match entries[done ..].binary_search_by(|e| e.0.cmp(&k_i)) {
+                    Ok(n) | Err(n) => done + n
+                }
+            } else {
+                entries.len()
+            };
+            let inserted;
+            check!(self.insert_group(&mut c, &entries[done .. end]), inserted);
+            done += inserted.0;
+            added += inserted.1;
+        }
+        if x.leaf { check!(self.update_node(x)); }
+        Ok((done, added))
+    }
+
+    /// Splits the root, which must be full, under a new root with a single key.
+    fn grow_root(&mut self) -> Result<(), Error> {
+        let mut s = Node::new(self.t, self.key_size);
+        let s_loc;
+        s.leaf = false;
+        s.len = 0;
+        s.children[0] = self.root_location;
+
+        check!(self.write_node(&mut s), s_loc);
+        self.root_location = s_loc;
+        s.loc = s_loc;
+
+        check!(self.split_child(&mut s, 0));
+        self.root = s;
+        Ok(())
+    }
+
+    fn insert_root(&mut self, k: &K, v: &V, replace: bool) -> Result<Option<V>, Error> {
+        if self.root.len == self.max_keys() {
+            check!(self.grow_root());
+        }
+        let mut root = self.root.clone();
+        let res = self.insert_nonfull(&mut root, k, v, replace);
+        self.root = root;
+        res
+    }
+
+    fn insert_nonfull(&mut self, x: &mut Node, k: &K, v: &V, replace: bool) -> Result<Option<V>, Error> {
+        let found;
+        check!(self.locate(x, k), found);
+        let (mut i, present) = found;
+
+        if present {
+            return self.replace_value(x, i, v, replace)
+        }
+
+        if x.leaf {
+            let moved = x.len as usize - i;
+            x.move_entries(i, i + 1, moved);
+            let entry;
+            check!(self.write_entry(k, v), entry);
+            x.set_entry(i, &entry.0, entry.1);
+            x.len += 1;
+            check!(self.update_node(&*x));
+            Ok(None)
+        } else {
             let x_child_i;
-            check!(self.node(x.children[i as usize]), x_child_i);
-            if x_child_i.len == NUM_KEYS as u64 {
-                check!(self.split_child(x, i as usize));
+            check!(self.node(x.children[i]), x_child_i);
+            if x_child_i.len == self.max_keys() {
+                check!(self.split_child(x, i));
                 let k_i;
-                check!(self.read_key(x.keys[i as usize]), k_i);
+                check!(self.node_key(x, i), k_i);
+                // The median that moved up into x may be the key being inserted
+                if *k == k_i { return self.replace_value(x, i, v, replace) }
                 if k > &k_i { i += 1 }
             }
             let mut c_i;
-            check!(self.node(x.children[i as usize]), c_i);
-            self.insert_nonfull(&mut c_i, k, v)
+            check!(self.node(x.children[i]), c_i);
+            self.insert_nonfull(&mut c_i, k, v, replace)
         }
     }
 
+    /// Points entry i of x at a newly written v, returning the value it replaces.
+    fn replace_value(&mut self, x: &mut Node, i: usize, v: &V, replace: bool) -> Result<Option<V>, Error> {
+        if !replace {
+            return Err(Error::new(ErrorKind::AlreadyExists, "key is already present in the tree"))
+        }
+        let old;
+        check!(self.read_value(x.values[i]), old);
+        let old_loc = x.values[i];
+        check!(self.free_record(VAL_FILE, old_loc));
+        let v_loc;
+        check!(self.write_val(v), v_loc);
+        x.values[i] = v_loc;
+        check!(self.update_node(&*x));
+        Ok(Some(old))
+    }
+
     pub fn contains_key(&mut self, k: &K) -> Result<bool, Error> {
         let root = self.root_location;
         self.contains_key_rec(k, root)
@@ -244,118 +829,696 @@ impl<K, V> PBTree<K, V>
     fn contains_key_rec(&mut self, k: &K, pos: u64) -> Result<bool, Error> {
         let x;
         check!(self.node(pos), x);
-        if x.len == 0 { return Ok(false) }
-
-        let mut k_i: K;
-        check!(self.read_key(x.keys[0]), k_i);
-
-        let mut i = 0;
-        while i < x.len && k > &k_i {
-            i += 1;
-            if i < x.len { check!(self.read_key(x.keys[i as usize]), k_i); }
-        }
+        let found;
+        check!(self.locate(&x, k), found);
+        let (i, present) = found;
 
-        if i < x.len && k == &k_i   { Ok(true) }
-        else if x.leaf              { Ok(false) }
-        else                        { self.contains_key_rec(k, x.children[i as usize]) }
+        if present          { Ok(true) }
+        else if x.leaf      { Ok(false) }
+        else                { self.contains_key_rec(k, x.children[i]) }
     }
 
     pub fn search(&mut self, k: &K) -> Result<Option<V>, Error> {
+        check!(self.check_usable());
         let r = self.root.clone();
         self.search_rec(r, k)
     }
 
     fn search_rec(&mut self, n: Node, k: &K) -> Result<Option<V>, Error> {
-        if n.len == 0 { return Ok(None); }
-
-        let mut k_i: K;
-        check!(self.read_key(n.keys[0]), k_i);
-
-        let mut i = 0;
-        while i < n.len && k > &k_i {
-            i += 1;
-            if i < n.len {
-                check!(self.read_key(n.keys[i as usize]), k_i);
-            }
-        }
+        let found;
+        check!(self.locate(&n, k), found);
+        let (i, present) = found;
 
-        if i < n.len && *k == k_i {
+        if present {
             let ret;
-            check!(self.read_value(n.values[i as usize]), ret);
+            check!(self.read_value(n.values[i]), ret);
             Ok(Some(ret))
         } else if n.leaf {
             Ok(None)
         } else {
             let next;
-            check!(self.node(n.children[i as usize]), next);
+            check!(self.node(n.children[i]), next);
             self.search_rec(next, k)
         }
     }
 
+    /// Removes the entry with key k from the tree, returning its value if it was present.
+    pub fn remove(&mut self, k: &K) -> Result<Option<V>, Error> {
+        check!(self.begin());
+        let res = self.remove_root(k);
+        if let Ok(Some(_)) = res { self.len -= 1; self.note_changed(k); }
+        self.finish(res)
+    }
+
+    fn remove_root(&mut self, k: &K) -> Result<Option<V>, Error> {
+        let mut root = self.root.clone();
+        let removed;
+        check!(self.remove_rec(&mut root, k), removed);
+
+        // The root lost its last key to a merge, so its only child becomes the new root
+        if root.len == 0 && !root.leaf {
+            check!(self.free_node(root.loc));
+            self.root_location = root.children[0];
+            let loc = self.root_location;
+            check!(self.node(loc), root);
+        }
+        self.root = root;
+        Ok(removed)
+    }
+
+    /// Removes k from the subtree rooted at x. Every node this descends into has at least t keys
+    /// (except the root), so a key can always be taken out of it without underflowing.
+    fn remove_rec(&mut self, x: &mut Node, k: &K) -> Result<Option<V>, Error> {
+        let found;
+        check!(self.locate(x, k), found);
+        let (i, present) = found;
+
+        if present && x.leaf {
+            let v;
+            check!(self.read_value(x.values[i]), v);
+            let (key, v_loc) = x.entry(i);
+            check!(self.free_entry(&key, v_loc));
+            let moved = x.len as usize - 1 - i;
+            x.move_entries(i + 1, i, moved);
+            x.len -= 1;
+            check!(self.update_node(x));
+            Ok(Some(v))
+        } else if present {
+            let v;
+            check!(self.read_value(x.values[i]), v);
+            let mut y;
+            check!(self.node(x.children[i]), y);
+            let (key, v_loc) = x.entry(i);
+            if y.len >= self.t {
+                // Replace k with its predecessor
+                check!(self.free_entry(&key, v_loc));
+                let entry;
+                check!(self.remove_max(&mut y), entry);
+                x.set_entry(i, &entry.0, entry.1);
+                check!(self.update_node(x));
+                return Ok(Some(v))
+            }
+            let mut z;
+            check!(self.node(x.children[i + 1]), z);
+            if z.len >= self.t {
+                // Replace k with its successor
+                check!(self.free_entry(&key, v_loc));
+                let entry;
+                check!(self.remove_min(&mut z), entry);
+                x.set_entry(i, &entry.0, entry.1);
+                check!(self.update_node(x));
+                return Ok(Some(v))
+            }
+            // Both neighbours are minimal: merge k and z into y, then remove k from y
+            let mut y;
+            check!(self.merge_children(x, i), y);
+            self.remove_rec(&mut y, k)
+        } else if x.leaf {
+            Ok(None)
+        } else {
+            let fixed;
+            check!(self.fix_child(x, i), fixed);
+            let (_, mut c) = fixed;
+            self.remove_rec(&mut c, k)
+        }
+    }
+
+    /// Removes the largest entry in the subtree rooted at x, returning its key slot and value location.
+    fn remove_max(&mut self, x: &mut Node) -> Result<(Vec<u8>, u64), Error> {
+        if x.leaf {
+            x.len -= 1;
+            let entry = x.entry(x.len as usize);
+            check!(self.update_node(x));
+            Ok(entry)
+        } else {
+            let i = x.len as usize;
+            let fixed;
+            check!(self.fix_child(x, i), fixed);
+            let (_, mut c) = fixed;
+            self.remove_max(&mut c)
+        }
+    }
+
+    /// Removes the smallest entry in the subtree rooted at x, returning its key slot and value location.
+    fn remove_min(&mut self, x: &mut Node) -> Result<(Vec<u8>, u64), Error> {
+        if x.leaf {
+            let entry = x.entry(0);
+            let moved = x.len as usize - 1;
+            x.move_entries(1, 0, moved);
+            x.len -= 1;
+            check!(self.update_node(x));
+            Ok(entry)
+        } else {
+            let fixed;
+            check!(self.fix_child(x, 0), fixed);
+            let (_, mut c) = fixed;
+            self.remove_min(&mut c)
+        }
+    }
+
+    /// Makes sure child i of x has at least t keys before it is descended into, borrowing a key
+    /// from a sibling through x or merging with a sibling. Returns the index and contents of the
+    /// child to descend into, which moves left by one if it was merged into its left sibling.
+    fn fix_child(&mut self, x: &mut Node, i: usize) -> Result<(usize, Node), Error> {
+        let mut c;
+        check!(self.node(x.children[i]), c);
+        if c.len >= self.t { return Ok((i, c)) }
+
+        if i > 0 {
+            let mut l;
+            check!(self.node(x.children[i - 1]), l);
+            if l.len >= self.t {
+                // Rotate the separator down into c and the last key of l up into x
+                let moved = c.len as usize;
+                c.move_entries(0, 1, moved);
+                if !c.leaf { for j in (0 .. c.len as usize + 1).rev() { c.children[j + 1] = c.children[j]; } }
+                c.copy_entries(0, x, i - 1, 1);
+                if !c.leaf { c.children[0] = l.children[l.len as usize]; }
+                c.len += 1;
+                l.len -= 1;
+                x.copy_entries(i - 1, &l, l.len as usize, 1);
+                check!(self.update_node(&l));
+                check!(self.update_node(&c));
+                check!(self.update_node(x));
+                return Ok((i, c))
+            }
+        }
+        if i < x.len as usize {
+            let mut r;
+            check!(self.node(x.children[i + 1]), r);
+            if r.len >= self.t {
+                // Rotate the separator down into c and the first key of r up into x
+                let end = c.len as usize;
+                c.copy_entries(end, x, i, 1);
+                if !c.leaf { c.children[c.len as usize + 1] = r.children[0]; }
+                c.len += 1;
+                x.copy_entries(i, &r, 0, 1);
+                let moved = r.len as usize - 1;
+                r.move_entries(1, 0, moved);
+                if !r.leaf { for j in 0 .. r.len as usize { r.children[j] = r.children[j + 1]; } }
+                r.len -= 1;
+                check!(self.update_node(&r));
+                check!(self.update_node(&c));
+                check!(self.update_node(x));
+                return Ok((i, c))
+            }
+            let merged;
+            check!(self.merge_children(x, i), merged);
+            Ok((i, merged))
+        } else {
+            let merged;
+            check!(self.merge_children(x, i - 1), merged);
+            Ok((i - 1, merged))
+        }
+    }
+
+    /// Merges child i + 1 of x and the key separating it from child i into child i,
+    /// returning the merged child.
+    fn merge_children(&mut self, x: &mut Node, i: usize) -> Result<Node, Error> {
+        let mut y;
+        check!(self.node(x.children[i]), y);
+        let z;
+        check!(self.node(x.children[i + 1]), z);
+
+        let y_len = y.len as usize;
+        y.copy_entries(y_len, x, i, 1);
+        y.copy_entries(y_len + 1, &z, 0, z.len as usize);
+        if !y.leaf { for j in 0 .. z.len as usize + 1 { y.children[y_len + 1 + j] = z.children[j]; } }
+        y.len += 1 + z.len;
+
+        let moved = x.len as usize - 1 - i;
+        x.move_entries(i + 1, i, moved);
+        for j in i + 1 .. x.len as usize { x.children[j] = x.children[j + 1]; }
+        x.len -= 1;
+
+        check!(self.update_node(&y));
+        check!(self.update_node(x));
+        check!(self.free_node(z.loc));
+        Ok(y)
+    }
+
+    /// Finds the index of the first key in x that is not less than k, and whether that key is k.
+    /// This is a binary search, so only about log2(len) keys of x are read.
+    pub(crate) fn locate(&mut self, x: &Node, k: &K) -> Result<(usize, bool), Error> {
+        let (mut lo, mut hi) = (0, x.len as usize);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let ord;
+            check!(self.cmp_key(k, x, mid), ord);
+            match ord {
+                Ordering::Less => hi = mid,
+                Ordering::Greater => lo = mid + 1,
+                Ordering::Equal => return Ok((mid, true))
+            }
+        }
+        Ok((lo, false))
+    }
+
+    /// Writes the header to the start of the treefile.
+    fn write_header(&mut self) -> Result<(), Error> {
+        let header = Header::new::<K, V>(self.root_location, self.t, self.inline_key_size(), self.len, self.free.clone());
+        let mut buf = vec![];
+        check!(header.raw_serialize(&mut buf));
+        self.write_at(TREE_FILE, 0, &buf)
+    }
+
     #[inline(always)]
-    fn write_entry(&mut self, k: &K, v: &V) -> Result<(u64, u64), Error> {
-        let key_pos;
+    fn write_entry(&mut self, k: &K, v: &V) -> Result<(Vec<u8>, u64), Error> {
+        let key;
         let val_pos;
-        check!(self.write_key(k), key_pos);
+        check!(self.write_key(k), key);
         check!(self.write_val(v), val_pos);
 
-        Ok((key_pos, val_pos))
+        Ok((key, val_pos))
 
     }
 
+    /// Writes k to the key file, unless keys are inline, and returns the key slot for it.
     #[inline(always)]
-    fn write_key(&mut self, k: &K) -> Result<u64, Error> {
+    fn write_key(&mut self, k: &K) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![];
+        check!(k.raw_serialize(&mut buf));
+        if self.inline_keys {
+            if buf.len() as u64 != self.key_size {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                    format!("key {:?} is {} bytes long, but the tree holds inline keys of {} bytes", k, buf.len(), self.key_size)));
+            }
+            return Ok(buf)
+        }
         let pos;
-        check!(self.keyfile.seek(SeekFrom::End(0)), pos);
-        check!(k.raw_serialize(&mut self.keyfile));
-        Ok(pos)
+        check!(self.write_record(KEY_FILE, buf), pos);
+        Ok(loc_slot(pos))
     }
 
     #[inline(always)]
     fn write_val(&mut self, v: &V) -> Result<u64, Error> {
-        let pos;
-        check!(self.valfile.seek(SeekFrom::End(0)), pos);
-        check!(v.raw_serialize(&mut self.valfile));
+        let mut buf = vec![];
+        check!(v.raw_serialize(&mut buf));
+        self.write_record(VAL_FILE, buf)
+    }
+
+    /// Writes a serialized key or value to free space in its file if there is a large enough
+    /// block, and to the end of the file otherwise.
+    fn write_record(&mut self, file_id: u8, mut buf: Vec<u8>) -> Result<u64, Error> {
+        // Pad records so they can be put on a free list later
+        let size = record_size(buf.len() as u64);
+        buf.resize(size as usize, 0);
+        let found;
+        check!(self.take_free(file_id, buf.len() as u64), found);
+        let pos = match found {
+            Some(pos) => pos,
+            None => self.file(file_id).len()
+        };
+        check!(self.write_at(file_id, pos, &buf));
         Ok(pos)
     }
 
     #[inline(always)]
     fn write_node(&mut self, node: &mut Node) -> Result<u64, Error> {
-        let pos;
-        check!(self.treefile.seek(SeekFrom::End(0)), pos);
+        // Reuse a freed node if there is one
+        let pos = if self.free.nodes == NONE {
+            self.treefile.len()
+        } else {
+            let pos = self.free.nodes;
+            let next;
+            check!(self.read_u64(TREE_FILE, pos), next);
+            self.free.nodes = next;
+            pos
+        };
         node.loc = pos;
-        check!(node.raw_serialize(&mut self.treefile));
+        let mut buf = vec![];
+        check!(node.raw_serialize(&mut buf));
+        check!(self.write_at(TREE_FILE, pos, &buf));
         Ok(pos)
     }
 
     #[inline(always)]
     fn update_node(&mut self, node: &Node) -> Result<(), Error> {
-        check!(self.treefile.seek(SeekFrom::Start(node.loc)));
-        check!(node.raw_serialize(&mut self.treefile));
+        let mut buf = vec![];
+        check!(node.raw_serialize(&mut buf));
+        check!(self.write_at(TREE_FILE, node.loc, &buf));
         self.node_cache.update(node);
         Ok(())
     }
 
+    /// Puts the node at loc on the free list of nodes.
+    fn free_node(&mut self, loc: u64) -> Result<(), Error> {
+        self.node_cache.remove(loc);
+        let mut buf = vec![];
+        check!(self.free.nodes.raw_serialize(&mut buf));
+        check!(self.write_at(TREE_FILE, loc, &buf));
+        self.free.nodes = loc;
+        Ok(())
+    }
+
+    /// Frees the key and value of an entry that has been removed. Inline keys go with their slot.
+    fn free_entry(&mut self, key: &[u8], v_loc: u64) -> Result<(), Error> {
+        if !self.inline_keys {
+            check!(self.free_record(KEY_FILE, slot_loc(key)));
+        }
+        self.free_record(VAL_FILE, v_loc)
+    }
+
+    /// Puts the key or value at pos on a free list. It is read first to find out how large it is.
+    fn free_record(&mut self, file_id: u8, pos: u64) -> Result<(), Error> {
+        let end = {
+            let mut reader = Reader::new(self.file(file_id), pos);
+            let read = if file_id == KEY_FILE {
+                K::raw_deserialize(&mut reader).map(|_| ())
+            } else {
+                V::raw_deserialize(&mut reader).map(|_| ())
+            };
+            check!(read);
+            reader.pos
+        };
+        if file_id == KEY_FILE { self.key_cache.remove(pos); }
+        self.free_block(file_id, pos, record_size(end - pos))
+    }
+
+    /// Adds the size bytes at pos to the free list of their class.
+    fn free_block(&mut self, file_id: u8, pos: u64, size: u64) -> Result<(), Error> {
+        let class = class_of(size);
+        let mut buf = vec![];
+        check!(self.free_heads(file_id)[class].raw_serialize(&mut buf));
+        if size >= MIN_SIZED_BLOCK {
+            check!(size.raw_serialize(&mut buf));
+        }
+        check!(self.write_at(file_id, pos, &buf));
+        self.free_heads(file_id)[class] = pos;
+        self.freed_since_merge[(file_id - KEY_FILE) as usize] += 1;
+        Ok(())
+    }
+
+    /// Takes a free block of at least size bytes out of the key or value file's free lists,
+    /// returning its location. If there is none, adjacent free blocks are merged and the lists
+    /// searched again, as long as enough blocks have been freed since the last merge to pay
+    /// for walking every list.
+    fn take_free(&mut self, file_id: u8, size: u64) -> Result<Option<u64>, Error> {
+        let found;
+        check!(self.find_free(file_id, size), found);
+        let i = (file_id - KEY_FILE) as usize;
+        if found.is_some() || self.freed_since_merge[i] <= self.blocks_after_merge[i] / 2 {
+            return Ok(found)
+        }
+        check!(self.merge_free(file_id));
+        self.find_free(file_id, size)
+    }
+
+    /// Replaces the free lists of the key or value file with lists in which no two blocks are
+    /// next to each other in the file. Splitting blocks for records of other sizes would
+    /// otherwise break the free space up into ever smaller blocks.
+    fn merge_free(&mut self, file_id: u8) -> Result<(), Error> {
+        let mut blocks = vec![];
+        for class in 0 .. self.free_heads(file_id).len() {
+            let mut block = self.free_heads(file_id)[class];
+            while block != NONE {
+                let next;
+                check!(self.read_u64(file_id, block), next);
+                let size = match exact_size(class) {
+                    Some(size) => size,
+                    None => {
+                        let size;
+                        check!(self.read_u64(file_id, block + 8), size);
+                        size
+                    }
+                };
+                blocks.push((block, size));
+                block = next;
+            }
+            self.free_heads(file_id)[class] = NONE;
+        }
+        blocks.sort();
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for (pos, size) in blocks {
+            match merged.last_mut() {
+                Some(last) if last.0 + last.1 == pos => last.1 += size,
+                _ => merged.push((pos, size))
+            }
+        }
+        // Freed last, the blocks nearest the start of the file end up at the heads of the lists
+        for &(pos, size) in merged.iter().rev() {
+            check!(self.free_block(file_id, pos, size));
+        }
+        let i = (file_id - KEY_FILE) as usize;
+        self.freed_since_merge[i] = 0;
+        self.blocks_after_merge[i] = merged.len() as u64;
+        Ok(())
+    }
+
+    /// Takes a free block of at least size bytes out of the key or value file's free lists,
+    /// returning its location. Whatever is left of the block is freed again. size must be a
+    /// multiple of MIN_RECORD, as every block is.
+    fn find_free(&mut self, file_id: u8, size: u64) -> Result<Option<u64>, Error> {
+        let first = class_of(size);
+        let last = self.free_heads(file_id).len() - 1;
+        for class in first ..= last {
+            // Blocks in the size's own class may be too small, and so may those in the last
+            // class, which has no upper bound. Every block in the other classes is large enough.
+            let searched = if exact_size(class).is_none() && (class == first || class == last) { FREE_SEARCH } else { 1 };
+            let mut prev = NONE;
+            let mut block = self.free_heads(file_id)[class];
+            for _ in 0 .. searched {
+                if block == NONE { break }
+                let next;
+                check!(self.read_u64(file_id, block), next);
+                let block_size = match exact_size(class) {
+                    Some(block_size) => block_size,
+                    None => {
+                        let block_size;
+                        check!(self.read_u64(file_id, block + 8), block_size);
+                        block_size
+                    }
+                };
+                if block_size < size {
+                    prev = block;
+                    block = next;
+                    continue
+                }
+
+                // Unlink the block
+                if prev == NONE {
+                    self.free_heads(file_id)[class] = next;
+                } else {
+                    let mut buf = vec![];
+                    check!(next.raw_serialize(&mut buf));
+                    check!(self.write_at(file_id, prev, &buf));
+                }
+                if block_size > size {
+                    check!(self.free_block(file_id, block + size, block_size - size));
+                }
+                return Ok(Some(block))
+            }
+        }
+        Ok(None)
+    }
+
+    /// The free lists of the key or value file.
+    fn free_heads(&mut self, file_id: u8) -> &mut [u64] {
+        if file_id == KEY_FILE { &mut self.free.keys } else { &mut self.free.values }
+    }
+
+    fn read_u64(&mut self, file_id: u8, pos: u64) -> Result<u64, Error> {
+        let mut buf = [0u8; 8];
+        check!(self.file(file_id).read_at(pos, &mut buf));
+        u64::raw_deserialize(&mut &buf[..])
+    }
+
+    fn file(&mut self, file_id: u8) -> &mut S {
+        match file_id {
+            TREE_FILE => &mut self.treefile,
+            KEY_FILE => &mut self.keyfile,
+            _ => &mut self.valfile
+        }
+    }
+
+    /// Writes bytes to the given file at pos. The write is logged to the write-ahead log, and
+    /// whatever it overwrites is remembered in case the operation has to be rolled back.
+    fn write_at(&mut self, file_id: u8, pos: u64, bytes: &[u8]) -> Result<(), Error> {
+        self.wal.log(file_id, pos, bytes);
+        let file = match file_id {
+            TREE_FILE => &mut self.treefile,
+            KEY_FILE => &mut self.keyfile,
+            _ => &mut self.valfile
+        };
+        if pos < file.len() {
+            let mut old = vec![0u8; cmp::min(bytes.len() as u64, file.len() - pos) as usize];
+            check!(file.read_at(pos, &mut old));
+            self.rollback.writes.push((file_id, pos, old));
+        }
+        file.write_at(pos, bytes)
+    }
+
+    /// Starts an operation that modifies the tree. Until it is committed, nothing it writes can
+    /// reach the disk.
+    fn begin(&mut self) -> Result<(), Error> {
+        check!(self.check_usable());
+        self.rollback.writes.clear();
+        self.rollback.ends = [self.treefile.len(), self.keyfile.len(), self.valfile.len()];
+        self.rollback.root_location = self.root_location;
+        self.rollback.root = self.root.clone();
+        self.rollback.len = self.len;
+        self.rollback.free = self.free.clone();
+        check!(self.treefile.pin_writes(true));
+        check!(self.keyfile.pin_writes(true));
+        self.valfile.pin_writes(true)
+    }
+
+    /// Ends the operation started by begin, committing it to the write-ahead log if it
+    /// succeeded and rolling it back otherwise. The header is rewritten first if the root, the
+    /// number of entries or the free lists changed.
+    fn finish<R>(&mut self, res: Result<R, Error>) -> Result<R, Error> {
+        let header_changed = self.root_location != self.rollback.root_location
+            || self.len != self.rollback.len
+            || self.free != self.rollback.free;
+        let res = match res {
+            Ok(r) => {
+                let header = if header_changed { self.write_header() } else { Ok(()) };
+                match header.and_then(|_| self.wal.commit()) {
+                    Ok(()) => Ok(r),
+                    Err(e) => Err(e)
+                }
+            },
+            Err(e) => Err(e)
+        };
+        if res.is_err() {
+            self.wal.abort();
+            let writes = mem::take(&mut self.rollback.writes);
+            for (file_id, pos, old) in writes.into_iter().rev() {
+                let file = match file_id {
+                    TREE_FILE => &mut self.treefile,
+                    KEY_FILE => &mut self.keyfile,
+                    _ => &mut self.valfile
+                };
+                check!(file.write_at(pos, &old));
+            }
+            check!(self.treefile.truncate(self.rollback.ends[0]));
+            check!(self.keyfile.truncate(self.rollback.ends[1]));
+            check!(self.valfile.truncate(self.rollback.ends[2]));
+            self.root_location = self.rollback.root_location;
+            self.root = self.rollback.root.clone();
+            self.len = self.rollback.len;
+            self.free = self.rollback.free.clone();
+            self.node_cache.clear();
+            // Keys written by the operation may be cached at locations it no longer uses
+            self.key_cache.clear();
+        }
+        if !self.hold_writes {
+            check!(self.unpin_writes());
+            check!(self.checkpoint());
+        }
+        res
+    }
+
+    /// Lets what the last operation wrote reach the storage.
+    pub(crate) fn unpin_writes(&mut self) -> Result<(), Error> {
+        check!(self.treefile.pin_writes(false));
+        check!(self.keyfile.pin_writes(false));
+        self.valfile.pin_writes(false)
+    }
+
+    /// Syncs the tree if the write-ahead log has grown past CHECKPOINT_SIZE.
+    pub(crate) fn checkpoint(&mut self) -> Result<(), Error> {
+        if self.wal.len > CHECKPOINT_SIZE {
+            check!(self.sync());
+        }
+        Ok(())
+    }
+
     #[inline(always)]
-    fn node(&mut self, pos: u64) -> Result<Node, Error> {
+    pub(crate) fn node(&mut self, pos: u64) -> Result<Node, Error> {
+        check!(self.check_usable());
         self.node_cache.get(pos, &mut self.treefile)
     }
 
+    /// Fails if a compaction left the tree unusable.
+    pub(crate) fn check_usable(&self) -> Result<(), Error> {
+        if self.poisoned {
+            return Err(Error::other(
+                "a compaction of the tree failed while replacing its files; open the tree again to finish it"));
+        }
+        Ok(())
+    }
+
     #[inline(always)]
     fn read_node(&mut self, pos: u64) -> Result<Node, Error> {
-        check!(self.treefile.seek(SeekFrom::Start(pos)));
-        Node::raw_deserialize(&mut self.treefile)
+        Node::read_at(&mut self.treefile, pos, self.t, self.key_size)
     }
 
     #[inline(always)]
-    fn read_value(&mut self, pos: u64) -> Result<V, Error> {
-        check!(self.valfile.seek(SeekFrom::Start(pos)));
-        V::raw_deserialize(&mut self.valfile)
+    pub(crate) fn read_value(&mut self, pos: u64) -> Result<V, Error> {
+        V::raw_deserialize(&mut Reader::new(&mut self.valfile, pos))
     }
 
+    /// How k compares to key i of node x. Keys read from the key file are cached for the next
+    /// comparison.
     #[inline(always)]
-    fn read_key(&mut self, pos: u64) -> Result<K, Error> {
-        check!(self.keyfile.seek(SeekFrom::Start(pos)));
-        K::raw_deserialize(&mut self.keyfile)
+    fn cmp_key(&mut self, k: &K, x: &Node, i: usize) -> Result<Ordering, Error> {
+        if self.inline_keys {
+            let k_i;
+            check!(self.node_key(x, i), k_i);
+            return Ok(k.cmp(&k_i))
+        }
+        let pos = x.key_loc(i);
+        if let Some(k_i) = self.key_cache.get(pos) {
+            return Ok(k.cmp(k_i))
+        }
+        let mut reader = Reader::new(&mut self.keyfile, pos);
+        let k_i;
+        check!(K::raw_deserialize(&mut reader), k_i);
+        let ord = k.cmp(&k_i);
+        let size = reader.pos - pos;
+        self.key_cache.insert(pos, k_i, size as usize);
+        Ok(ord)
+    }
+
+    /// Key i of node x.
+    #[inline(always)]
+    pub(crate) fn node_key(&mut self, x: &Node, i: usize) -> Result<K, Error> {
+        self.decode_key(x.key(i))
+    }
+
+    /// The key a key slot holds or refers to.
+    #[inline(always)]
+    pub(crate) fn decode_key(&mut self, key: &[u8]) -> Result<K, Error> {
+        if self.inline_keys {
+            K::raw_deserialize(&mut &key[..])
+        } else {
+            self.read_key(slot_loc(key))
+        }
+    }
+
+    /// Whether keys are kept in the nodes rather than the key file.
+    pub(crate) fn inline_keys(&self) -> bool {
+        self.inline_keys
+    }
+
+    /// The size of the key slots of the nodes.
+    pub(crate) fn key_size(&self) -> u64 {
+        self.key_size
+    }
+
+    /// The locations the last operation overwrote, as (file id, position). Writes past the end
+    /// of a file are left out. Empty if the operation was rolled back.
+    pub(crate) fn last_writes<'a>(&'a self) -> Box<dyn Iterator<Item=(u8, u64)> + 'a> {
+        Box::new(self.rollback.writes.iter().map(|&(file_id, pos, _)| (file_id, pos)))
+    }
+
+    /// The size of inline keys as recorded in the header, 0 if keys are not inline.
+    pub(crate) fn inline_key_size(&self) -> u64 {
+        if self.inline_keys { self.key_size } else { 0 }
+    }
+
+    #[inline(always)]
+    pub(crate) fn read_key(&mut self, pos: u64) -> Result<K, Error> {
+        K::raw_deserialize(&mut Reader::new(&mut self.keyfile, pos))
+    }
+}
+
+/// Fails with ErrorKind::InvalidInput unless t is a minimum degree a tree can have.
+fn check_degree(t: u64) -> Result<(), Error> {
+    if !(MIN_DEGREE..=MAX_DEGREE).contains(&t) {
+        return Err(Error::new(ErrorKind::InvalidInput,
+            format!("minimum degree must be between {} and {}, not {}", MIN_DEGREE, MAX_DEGREE, t)));
     }
+    Ok(())
 }
@@ -0,0 +1,219 @@
+use std::cmp;
+use std::fs::{ self, OpenOptions };
+use std::io::{ Error, ErrorKind, Read, Seek, SeekFrom, Write };
+use std::fmt::Debug;
+use raw_serde::*;
+use type_tag::TypeTag;
+use btree::{ PBTree, Options, MIN_DEGREE, MAX_DEGREE };
+use compact;
+use file_buffer::{ BufFile, check_slabs };
+use free_list::{ FreeLists, record_size };
+use header::{ Header, HEADER_SIZE };
+use node::{ Node, loc_slot, KEY_LOC_SIZE };
+use storage::{ Storage, FileStorage };
+
+/// The fraction of each node PBTree::bulk_load fills, leaving room for later inserts.
+pub const DEFAULT_FILL: f64 = 0.9;
+
+impl<K, V, S: FileStorage> PBTree<K, V, S>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
+
+    /// Creates a tree with the default minimum degree out of entries, which must be sorted by
+    /// key, truncating any files already at the path. This is much faster than inserting the
+    /// entries one at a time: every file is written front to back and each node only once.
+    pub fn bulk_load<P, I>(path: P, entries: I) -> Result<Self, Error>
+        where P: Into<String>, I: IntoIterator<Item=(K, V)> {
+        Self::bulk_load_with_options(path, Options::default(), entries)
+    }
+
+    /// Like bulk_load, but with nodes of minimum degree t, each filled to the given fraction of
+    /// its 2t - 1 keys, though never to fewer than t - 1. A fill of 1 packs the nodes full,
+    /// which makes for the smallest tree if it is not going to change; lower fills spare later
+    /// inserts from splitting nodes. Fails with ErrorKind::InvalidInput if t or fill is out of
+    /// range or the keys are not strictly increasing, in which case the files of the tree at the
+    /// path are left in an unspecified state. The spool file the load writes alongside them is
+    /// removed whether it succeeds or not.
+    pub fn bulk_load_with<P, I>(path: P, t: u64, fill: f64, entries: I) -> Result<Self, Error>
+        where P: Into<String>, I: IntoIterator<Item=(K, V)> {
+        Self::bulk_load_with_options(path, Options { degree: t, fill, ..Options::default() }, entries)
+    }
+
+    /// Like bulk_load_with, but with the degree, fill and slabs of the given options. Large slabs
+    /// make the files quicker to write front to back; the tree that is returned is opened with
+    /// the same options.
+    pub fn bulk_load_with_options<P, I>(_path: P, options: Options, entries: I) -> Result<Self, Error>
+        where P: Into<String>, I: IntoIterator<Item=(K, V)> {
+        let path = _path.into();
+        let (t, fill) = (options.degree, options.fill);
+        if !(MIN_DEGREE..=MAX_DEGREE).contains(&t) {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                format!("minimum degree must be between {} and {}, not {}", MIN_DEGREE, MAX_DEGREE, t)));
+        }
+        if !(fill > 0.0 && fill <= 1.0) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("fill must be above 0 and at most 1, not {}", fill)));
+        }
+        let max_keys = 2 * t - 1;
+        let keys_per_node = cmp::max((max_keys as f64 * fill).round() as u64, t - 1);
+
+        check!(check_slabs(options.slab_size, options.slabs));
+
+        check!(compact::discard(&path));
+        let res = Self::write_files(&path, &options, keys_per_node, entries);
+        let spool = path.clone() + ".bulk";
+        match res {
+            Ok(()) => check!(fs::remove_file(spool)),
+            Err(e) => {
+                let _ = fs::remove_file(spool);
+                return Err(e)
+            }
+        }
+        Self::open_with_options(path, options)
+    }
+
+    /// Writes the files of a tree at path holding entries, with up to keys_per_node keys in each
+    /// node, using a spool file at path.bulk that the caller must remove.
+    fn write_files<I>(path: &str, options: &Options, keys_per_node: u64, entries: I) -> Result<(), Error>
+        where I: IntoIterator<Item=(K, V)> {
+        let files;
+        check!(S::open_files(path, true, options), files);
+        let (mut treefile, mut keyfile, mut valfile) = files;
+        let _spooled;
+        check!(OpenOptions::new().read(true).write(true).truncate(true).create(true).open(path.to_string() + ".bulk"), _spooled);
+        let mut spooled;
+        check!(BufFile::new(_spooled), spooled);
+        // Any write-ahead log left at the path must not be replayed over the new tree
+        check!(OpenOptions::new().write(true).truncate(true).create(true).open(path.to_string() + ".wal"));
+
+        // Keys and values go straight to their files, and their locations to a spool file that
+        // the nodes are built from once the number of entries is known
+        let mut count = 0;
+        let mut last: Option<K> = None;
+        for (k, v) in entries {
+            if let Some(ref last) = last {
+                if k <= *last {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                        format!("bulk loaded keys must be strictly increasing, but {:?} came after {:?}", k, last)));
+                }
+            }
+            let (mut k_buf, mut v_buf) = (vec![], vec![]);
+            check!(k.raw_serialize(&mut k_buf));
+            check!(v.raw_serialize(&mut v_buf));
+            let (k_loc, v_loc);
+            check!(append_record(&mut keyfile, k_buf), k_loc);
+            check!(append_record(&mut valfile, v_buf), v_loc);
+            check!(spooled.write_all(&loc_slot(k_loc)));
+            check!(v_loc.raw_serialize(&mut spooled));
+            count += 1;
+            last = Some(k);
+        }
+
+        check!(spooled.seek(SeekFrom::Start(0)));
+        check!(write_tree::<K, V, _>(&mut treefile, options.degree, 0, keys_per_node, &mut spooled, count));
+        check!(keyfile.sync());
+        valfile.sync()
+    }
+}
+
+/// Appends a serialized key or value to file, padded as free_list::record_size has it, returning
+/// its location.
+pub fn append_record<S: Storage>(file: &mut S, mut buf: Vec<u8>) -> Result<u64, Error> {
+    let size = record_size(buf.len() as u64);
+    buf.resize(size as usize, 0);
+    let pos = file.len();
+    check!(file.write_at(pos, &buf));
+    Ok(pos)
+}
+
+/// Fills the empty treefile of a tree with keys of type K and values of type V, and inline keys
+/// of the given size unless it is 0: its header, followed by nodes built from count entries read
+/// from entries, as for build_nodes. The treefile is synced, but the key and value files the
+/// entries point into are not.
+pub fn write_tree<K: TypeTag, V: TypeTag, S: Storage>(treefile: &mut S, t: u64, inline_key_size: u64, fill: u64, entries: &mut dyn Read, count: u64)
+    -> Result<(), Error> {
+    let key_size = if inline_key_size == 0 { KEY_LOC_SIZE } else { inline_key_size };
+    // The header is written once the location of the root is known
+    check!(treefile.write_at(0, &vec![0u8; HEADER_SIZE as usize]));
+    let root;
+    check!(build_nodes(treefile, t, key_size, fill, entries, count), root);
+    check!(treefile.write_at(0, &Header::new::<K, V>(root, t, inline_key_size, count, FreeLists::new()).to_bytes()));
+    treefile.sync()
+}
+
+/// Builds the nodes of a tree bottom up, appending them to treefile, and returns the location of
+/// the root. entries holds count entries in key order, each a key slot of key_size bytes followed
+/// by the location of the value. Nodes get at most fill keys (between t - 1 and 2t - 1), and the
+/// entries are spread evenly over the nodes of each level, so no node but the root has fewer
+/// than t - 1.
+pub fn build_nodes<S: Storage>(treefile: &mut S, t: u64, key_size: u64, fill: u64, entries: &mut dyn Read, count: u64) -> Result<u64, Error> {
+    let mut level;
+    check!(build_level(treefile, t, key_size, fill, entries, count, None), level);
+    loop {
+        let (separators, locations): (Vec<u8>, Vec<u64>) = level;
+        if locations.len() == 1 { return Ok(locations[0]) }
+        let n = locations.len() as u64 - 1;
+        check!(build_level(treefile, t, key_size, fill, &mut &separators[..], n, Some(&locations)), level);
+    }
+}
+
+/// Reads an entry as written for build_nodes.
+fn read_entry(entries: &mut dyn Read, key_size: u64) -> Result<(Vec<u8>, u64), Error> {
+    let mut key = vec![0; key_size as usize];
+    check!(entries.read_exact(&mut key));
+    let v;
+    check!(u64::raw_deserialize(entries), v);
+    Ok((key, v))
+}
+
+/// Builds one level of nodes out of n entries, read from entries. Leaves are built if children
+/// is None; otherwise the nodes take n + 1 children from it. Returns the serialized entries that
+/// separate the nodes, which belong to the level above, and the locations of the nodes.
+fn build_level<S: Storage>(treefile: &mut S, t: u64, key_size: u64, fill: u64, entries: &mut dyn Read, n: u64, children: Option<&[u64]>)
+    -> Result<(Vec<u8>, Vec<u64>), Error> {
+    let nodes = num_nodes(n, t, fill);
+    let keys = n - (nodes - 1);
+    let mut separators = vec![];
+    let mut locations = vec![];
+    let mut next_child = 0;
+    for i in 0 .. nodes {
+        let len = keys / nodes + if i < keys % nodes { 1 } else { 0 };
+        let mut node = Node::new(t, key_size);
+        node.len = len;
+        node.leaf = children.is_none();
+        for j in 0 .. len as usize {
+            let entry;
+            check!(read_entry(entries, key_size), entry);
+            node.set_entry(j, &entry.0, entry.1);
+        }
+        if let Some(children) = children {
+            node.children[.. len as usize + 1].clone_from_slice(&children[next_child .. next_child + len as usize + 1]);
+            next_child += len as usize + 1;
+        }
+
+        let loc = treefile.len();
+        node.loc = loc;
+        let mut buf = vec![];
+        check!(node.raw_serialize(&mut buf));
+        check!(treefile.write_at(loc, &buf));
+        locations.push(loc);
+
+        if i + 1 < nodes {
+            let entry;
+            check!(read_entry(entries, key_size), entry);
+            separators.extend_from_slice(&entry.0);
+            let _ = entry.1.raw_serialize(&mut separators);
+        }
+    }
+    Ok((separators, locations))
+}
+
+/// How many nodes a level of n entries is split into. Each node but the last is followed by a
+/// separator, so there are n + 1 slots to spread out, between t and 2t per node.
+fn num_nodes(n: u64, t: u64, fill: u64) -> u64 {
+    let slots = n + 1;
+    let mut nodes = (slots + fill) / (fill + 1);
+    while nodes > 1 && slots / nodes < t {
+        nodes -= 1;
+    }
+    if nodes == 0 { 1 } else { nodes }
+}
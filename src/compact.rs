@@ -0,0 +1,296 @@
+#[cfg(test)]
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ Error, Seek, SeekFrom, Write };
+use std::ops::Bound;
+use std::path::Path;
+use std::fmt::Debug;
+use raw_serde::*;
+use type_tag::TypeTag;
+use btree::{ PBTree, Options };
+use bulk::{ append_record, write_tree };
+use file_buffer::BufFile;
+use iter::Range;
+use node::loc_slot;
+use storage::{ Storage, FileStorage };
+
+/// Appended to the path of a tree to name the files a compaction writes. A file with exactly
+/// this name marks a compaction that has been written in full and is being swapped in.
+const COMPACT: &str = ".compact";
+
+/// The files a compaction replaces, in the order they are swapped in. Only those the tree is
+/// kept in are written.
+const SWAPPED: [&str; 4] = [".key", ".val", ".tree", ".pbt"];
+
+#[cfg(test)]
+thread_local! {
+    /// Set by tests to make the next swap on this thread fail after its first rename, as if the
+    /// process had died there.
+    pub(crate) static FAIL_SWAP: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A compaction in progress. Entries are copied in key order into fresh key and value files,
+/// and the locations they were copied to are listed in an entries file, from which the nodes are
+/// built once everything has been copied.
+pub(crate) struct Compaction<K, S> {
+    /// The last key copied, None until the first has been.
+    cursor: Option<K>,
+    treefile: S,
+    keyfile: S,
+    valfile: S,
+    entries: BufFile,
+    /// The number of entries copied.
+    copied: u64,
+    /// Serialized keys at or before the cursor that were inserted, replaced or removed after
+    /// the cursor passed them. They are brought up to date once the copy is complete.
+    changed: BTreeSet<Vec<u8>>
+}
+
+impl<K, S: FileStorage> Compaction<K, S> {
+    fn start(path: &str, options: &Options) -> Result<Compaction<K, S>, Error> {
+        check!(discard(path));
+        let temp = path.to_string() + COMPACT;
+        let files;
+        check!(S::open_files(&temp, true, options), files);
+        let (treefile, keyfile, valfile) = files;
+        let _entries;
+        check!(OpenOptions::new().read(true).write(true).truncate(true).create(true).open(temp + ".entries"), _entries);
+        let entries;
+        check!(BufFile::new(_entries), entries);
+        Ok(Compaction {
+            cursor: None,
+            treefile,
+            keyfile,
+            valfile,
+            entries,
+            copied: 0,
+            changed: BTreeSet::new()
+        })
+    }
+}
+
+impl<K, V, S: FileStorage> PBTree<K, V, S>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
+
+    /// Rewrites the tree into fresh files, holding only its live nodes, keys and values, with
+    /// keys and values in key order and every node full. The new files replace the old ones
+    /// atomically: if the process dies part way, opening the tree finds either the old files or
+    /// the new ones. Returns the number of bytes reclaimed.
+    pub fn compact(&mut self) -> Result<u64, Error> {
+        loop {
+            let done;
+            check!(self.compact_step(usize::MAX), done);
+            if let Some(reclaimed) = done { return Ok(reclaimed) }
+        }
+    }
+
+    /// Does part of a compaction, copying at most max_entries entries, so a tree can be compacted
+    /// a little at a time while it is in use. The first call starts a compaction. Entries that
+    /// change between calls are caught up at the end. Once everything has been copied, the nodes
+    /// are built and swapped in; the call that does this returns the number of bytes reclaimed,
+    /// and the others return None. Fails with an error of kind Other if the tree was given its storage
+    /// rather than opened at a path.
+    ///
+    /// A compaction that fails before the new files start to replace the old ones is abandoned,
+    /// leaving the tree as it was. One that fails after leaves the tree unusable, and every
+    /// operation on it fails until it is opened again, which finishes the swap.
+    pub fn compact_step(&mut self, max_entries: usize) -> Result<Option<u64>, Error> {
+        check!(self.check_usable());
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Err(Error::other("only trees opened at a path can be compacted"))
+        };
+        let mut compaction = match self.compaction.take() {
+            Some(compaction) => compaction,
+            None => {
+                let compaction;
+                check!(Compaction::start(&path, &self.options), compaction);
+                compaction
+            }
+        };
+        let res = match self.copy_entries(&mut compaction, max_entries) {
+            Ok(false) => {
+                self.compaction = Some(compaction);
+                return Ok(None)
+            },
+            Ok(true) => self.finish_compaction(&path, compaction),
+            Err(e) => Err(e)
+        };
+        match res {
+            Ok(reclaimed) => Ok(Some(reclaimed)),
+            Err(e) => {
+                if !self.poisoned {
+                    let _ = discard(&path);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Copies up to max_entries entries after the cursor, returning whether every entry has
+    /// been copied.
+    fn copy_entries(&mut self, compaction: &mut Compaction<K, S>, max_entries: usize) -> Result<bool, Error> {
+        if max_entries == 0 { return Ok(false) }
+        let start = match compaction.cursor.take() {
+            Some(k) => Bound::Excluded(k),
+            None => Bound::Unbounded
+        };
+        let inline_keys = self.inline_keys();
+        let mut range = Range::new(self, start, Bound::Unbounded);
+        for _ in 0 .. max_entries {
+            let (k, v) = match range.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => return Err(e),
+                None => return Ok(true)
+            };
+            let (mut k_buf, mut v_buf) = (vec![], vec![]);
+            check!(k.raw_serialize(&mut k_buf));
+            check!(v.raw_serialize(&mut v_buf));
+            let key = if inline_keys {
+                k_buf
+            } else {
+                let k_loc;
+                check!(append_record(&mut compaction.keyfile, k_buf), k_loc);
+                loc_slot(k_loc)
+            };
+            let v_loc;
+            check!(append_record(&mut compaction.valfile, v_buf), v_loc);
+            check!(compaction.entries.write_all(&key));
+            check!(v_loc.raw_serialize(&mut compaction.entries));
+            compaction.copied += 1;
+            compaction.cursor = Some(k);
+        }
+        Ok(false)
+    }
+
+    /// Builds the nodes of the compacted tree, catches up on the entries that changed while it
+    /// was being copied, and swaps it in.
+    fn finish_compaction(&mut self, path: &str, compaction: Compaction<K, S>) -> Result<u64, Error> {
+        let temp = path.to_string() + COMPACT;
+        let Compaction { mut treefile, mut keyfile, mut valfile, mut entries, copied, changed, .. } = compaction;
+
+        let t = self.degree();
+        let inline_key_size = self.inline_key_size();
+        check!(entries.seek(SeekFrom::Start(0)));
+        check!(write_tree::<K, V, _>(&mut treefile, t, inline_key_size, 2 * t - 1, &mut entries, copied));
+        check!(keyfile.sync());
+        check!(valfile.sync());
+        drop((treefile, keyfile, valfile, entries));
+        check!(fs::remove_file(temp.clone() + ".entries"));
+
+        {
+            let mut compacted;
+            check!(PBTree::<K, V, S>::open_with_options(temp.clone(), self.options), compacted);
+            for buf in changed.iter() {
+                let k;
+                check!(K::raw_deserialize(&mut &buf[..]), k);
+                let v;
+                check!(self.search(&k), v);
+                match v {
+                    Some(v) => check!(compacted.insert(&k, &v)),
+                    None => check!(compacted.remove(&k))
+                };
+            }
+            check!(compacted.sync());
+        }
+        check!(fs::remove_file(temp.clone() + ".wal"));
+
+        let before = self.treefile.len() + self.keyfile.len() + self.valfile.len();
+        check!(self.sync());
+        check!(mark_swap(path));
+        // From here on the new files are bound to replace the old ones, by recover if need be, so
+        // the tree must not be used until it has been reopened
+        if let Err(e) = finish_swap(path).and_then(|_| self.reopen()) {
+            self.poisoned = true;
+            return Err(e)
+        }
+        let after = self.treefile.len() + self.keyfile.len() + self.valfile.len();
+        Ok(before.saturating_sub(after))
+    }
+}
+
+impl<K, V, S: Storage> PBTree<K, V, S>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
+
+    /// Notes that the entry with key k changed, in case a compaction has already copied it.
+    pub(crate) fn note_changed(&mut self, k: &K) {
+        if let Some(ref mut compaction) = self.compaction {
+            let passed = match compaction.cursor {
+                Some(ref cursor) => k <= cursor,
+                None => false
+            };
+            if passed {
+                let mut buf = vec![];
+                // Writing to a Vec can't fail
+                let _ = k.raw_serialize(&mut buf);
+                compaction.changed.insert(buf);
+            }
+        }
+    }
+}
+
+/// Finishes swapping in a compaction that was interrupted after it had been written in full, and
+/// deletes what is left of any other compaction of the tree at path.
+pub(crate) fn recover(path: &str) -> Result<(), Error> {
+    if Path::new(&(path.to_string() + COMPACT)).exists() {
+        check!(finish_swap(path));
+    }
+    discard(path)
+}
+
+/// Deletes the files of any compaction of the tree at path, without swapping them in.
+pub(crate) fn discard(path: &str) -> Result<(), Error> {
+    for ext in [".tree", ".key", ".val", ".pbt", ".wal", ".entries", ""].iter() {
+        let name = path.to_string() + COMPACT + ext;
+        if Path::new(&name).exists() {
+            check!(fs::remove_file(&name));
+        }
+    }
+    Ok(())
+}
+
+/// Marks the compaction of the tree at path, which must be complete and synced, as ready to be
+/// swapped in. Once the marker is on disk the swap is bound to happen, by recover if need be.
+fn mark_swap(path: &str) -> Result<(), Error> {
+    let marker;
+    check!(File::create(path.to_string() + COMPACT), marker);
+    check!(marker.sync_all());
+    sync_dir(path);
+    Ok(())
+}
+
+/// Replaces the files of the tree at path with those of its compaction, which has been marked
+/// by mark_swap.
+fn finish_swap(path: &str) -> Result<(), Error> {
+    for ext in SWAPPED.iter() {
+        let from = path.to_string() + COMPACT + ext;
+        if Path::new(&from).exists() {
+            check!(fs::rename(&from, path.to_string() + ext));
+            #[cfg(test)]
+            {
+                if FAIL_SWAP.with(|fail| fail.replace(false)) {
+                    return Err(Error::other("injected failure"))
+                }
+            }
+        }
+    }
+    sync_dir(path);
+    check!(fs::remove_file(path.to_string() + COMPACT));
+    sync_dir(path);
+    Ok(())
+}
+
+/// Makes renames and deletions in the directory holding path durable, where the platform
+/// allows directories to be synced.
+fn sync_dir(path: &str) {
+    let dir = match Path::new(path).parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => Path::new(".").to_path_buf()
+    };
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
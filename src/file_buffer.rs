@@ -1,19 +1,17 @@
-use std::cmp::{ Ord, Ordering };
 use std::fs::File;
-use std::io::{ Error, Seek, SeekFrom, Write, Read };
+use std::io::{ Error, ErrorKind, Seek, SeekFrom, Write, Read };
 use std::collections::HashMap;
 #[allow(unused_imports)]
 use raw_serde::*;
 use std::cmp;
+use slab_policy::{ SlabPolicy, LfuPolicy };
+use storage::SharedRead;
 
-/// Slab size MUST be a power of 2!
-const SLAB_SIZE: usize = 1024*1024; // 1 Megabyte
-/// Used to turn a file index into an array index (since SLAB_SIZE is a power of two,
-/// subtracting one from it will yield all ones, and anding it with a number will
-/// yield only the lowest n bits, where SLAB_SIZE = 2^n
-const SLAB_MASK: u64 = SLAB_SIZE as u64 - 1;
+/// The slab size used unless another is given. Slab sizes MUST be powers of 2!
+pub const DEFAULT_SLAB_SIZE: usize = 1024*1024; // 1 Megabyte
 
-const DEFAULT_NUM_SLABS: usize = 16;
+/// The number of slabs a BufFile keeps unless told otherwise.
+pub const DEFAULT_NUM_SLABS: usize = 16;
 
 /// A struct representing a section of a file
 pub struct Slab {
@@ -21,28 +19,83 @@ pub struct Slab {
     pub dat: Vec<u8>,
     /// First byte in the file that is contained in this slab
     start: u64,
-    /// Number of times this slab has been accessed.
-    uses: u64
+    /// Pinned slabs hold writes that must not reach the disk yet, so they are never evicted.
+    pinned: bool,
+    /// The range of indices into dat that have been written to since the slab was last
+    /// written to disk, if any. Only this range is ever written back.
+    dirty: Option<(usize, usize)>
+}
+
+/// Fails with ErrorKind::InvalidInput unless slab_size is a power of two and slabs is at least 1,
+/// as a BufFile needs.
+pub fn check_slabs(slab_size: usize, slabs: usize) -> Result<(), Error> {
+    if !slab_size.is_power_of_two() {
+        return Err(Error::new(ErrorKind::InvalidInput,
+            format!("slab size must be a power of two, not {}", slab_size)));
+    }
+    if slabs == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "a BufFile must keep at least one slab"));
+    }
+    Ok(())
 }
 
 impl Slab {
     /// Creates a new slab, drawing it's data from the given file at the given location
-    /// Location should be at the beginning of a slab (e.g. a muitiple of SLAB_SIZE)
-    pub fn new(loc: u64, file: &mut File) -> Result<Slab, Error> {
+    /// Location should be at the beginning of a slab (e.g. a muitiple of size)
+    pub fn new(loc: u64, size: usize, file: &mut File) -> Result<Slab, Error> {
         check!(file.seek(SeekFrom::Start(loc)));
-        let mut dat = vec![0u8; SLAB_SIZE];
-        check!(file.read(&mut dat[0..]));
+        let mut dat = vec![0u8; size];
+        // Past the end of the file the slab reads as zeros
+        let mut read = 0;
+        while read < size {
+            let n;
+            check!(file.read(&mut dat[read..]), n);
+            if n == 0 { break }
+            read += n;
+        }
         Ok(Slab {
-            dat: dat,
+            dat,
             start: loc,
-            uses: 0
+            pinned: false,
+            dirty: None
         })
     }
 
-    /// Write the slab to disk
-    pub fn write(&self, file: &mut File) -> Result<(), Error> {
-        check!(file.seek(SeekFrom::Start(self.start)));
-        check!(file.write_all(&self.dat[0..]));
+    /// Records that dat[from..to] has been written to.
+    pub fn mark_dirty(&mut self, from: usize, to: usize) {
+        self.dirty = match self.dirty {
+            Some((start, end)) => Some((cmp::min(start, from), cmp::max(end, to))),
+            None => Some((from, to))
+        };
+    }
+
+    /// Write the dirty part of the slab to disk. Nothing past `end` (the logical end of the
+    /// file) is written, so flushing never pads the file out to a multiple of the slab size.
+    pub fn write(&mut self, file: &mut File, end: u64) -> Result<(), Error> {
+        let start = self.start;
+        self.write_range(file, start, end)
+    }
+
+    /// Write the dirty part of the slab that lies within the file indices [from, to) to disk.
+    pub fn write_range(&mut self, file: &mut File, from: u64, to: u64) -> Result<(), Error> {
+        let (dirty_start, dirty_end) = match self.dirty {
+            Some((start, end)) => (self.start + start as u64, self.start + end as u64),
+            None => return Ok(())
+        };
+        let from = cmp::max(from, dirty_start);
+        let to = cmp::min(to, dirty_end);
+        if from >= to { return Ok(()) }
+        check!(file.seek(SeekFrom::Start(from)));
+        check!(file.write_all(&self.dat[(from - self.start) as usize .. (to - self.start) as usize]));
+
+        // Shrink the dirty range by what was written, unless that would split it in two
+        let (start, end) = ((from - self.start) as usize, (to - self.start) as usize);
+        self.dirty = match self.dirty {
+            Some((dirty_start, dirty_end)) if start <= dirty_start && end >= dirty_end => None,
+            Some((dirty_start, dirty_end)) if start <= dirty_start => Some((end, dirty_end)),
+            Some((dirty_start, dirty_end)) if end >= dirty_end => Some((dirty_start, start)),
+            dirty => dirty
+        };
         Ok(())
     }
 }
@@ -50,6 +103,14 @@ impl Slab {
 pub struct BufFile {
     /// The maximum number of slabs this BufFile can have
     slabs: usize,
+    /// The number of bytes in each slab, always a power of two
+    slab_size: usize,
+    /// Used to turn a file index into an array index (since slab_size is a power of two,
+    /// subtracting one from it will yield all ones, and anding it with a number will
+    /// yield only the lowest n bits, where slab_size = 2^n
+    slab_mask: u64,
+    /// Decides which slab to evict when a new one is needed
+    policy: Box<dyn SlabPolicy>,
     /// Used to quickly map a file index to an array index (to index self.dat)
     map: HashMap<u64, usize>,
     /// Contains the actual slabs
@@ -60,17 +121,39 @@ pub struct BufFile {
     /// This does not reflect the actual location of the cursor in the file.
     pub cursor: u64,
     /// The file index that is the end of the file.
-    pub end: u64
+    pub end: u64,
+    /// While set, every slab that is written to is pinned.
+    pin_writes: bool
 }
 
 impl BufFile {
     /// Creates a new BufFile.
-    pub fn new(mut file: File) -> Result<BufFile, Error> {
+    pub fn new(file: File) -> Result<BufFile, Error> {
         Self::with_capacity(DEFAULT_NUM_SLABS, file)
     }
 
-    /// Creates a new BufFile with the specified number of slabs.
-    pub fn with_capacity(slabs: usize, mut file: File) -> Result<BufFile, Error> {
+    /// Creates a new BufFile with the specified number of slabs, evicting the least frequently
+    /// used slab when it needs room for another. with_policy takes any other policy, e.g.
+    /// `PolicyKind::Arc.new_policy(slabs)`.
+    pub fn with_capacity(slabs: usize, file: File) -> Result<BufFile, Error> {
+        Self::with_policy(Box::new(LfuPolicy::new(slabs)), file)
+    }
+
+    /// Creates a new BufFile that keeps as many slabs as the policy's capacity, and evicts the
+    /// slabs it chooses.
+    pub fn with_policy(policy: Box<dyn SlabPolicy>, file: File) -> Result<BufFile, Error> {
+        Self::with_slab_size(DEFAULT_SLAB_SIZE, policy, file)
+    }
+
+    /// Creates a new BufFile whose slabs are slab_size bytes long. Small slabs suit random
+    /// access, since a miss reads only slab_size bytes, while large slabs suit sequential
+    /// access. Fails if slab_size is not a power of two.
+    pub fn with_slab_size(slab_size: usize, policy: Box<dyn SlabPolicy>, mut file: File) -> Result<BufFile, Error> {
+        if !slab_size.is_power_of_two() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                format!("slab size must be a power of two, not {}", slab_size)));
+        }
+
         // Find the end of the file, in case the file isnt empty.
         let end;
         check!(file.seek(SeekFrom::End(0)), end);
@@ -78,22 +161,132 @@ impl BufFile {
         // Move the cursor back to the start of the file.
         check!(file.seek(SeekFrom::Start(0)));
         Ok(BufFile {
-            slabs: slabs,  // Maximum of 32 slabs
+            slabs: policy.capacity(),
+            slab_size,
+            slab_mask: slab_size as u64 - 1,
+            policy,
             dat: vec![],
             map: HashMap::new(),
             file,
             cursor: 0,  // Since the cursor is at the start of the file
-            end
+            end,
+            pin_writes: false
         })
     }
 
+    /// Starts or stops pinning written slabs. While pinning, slabs that are written to stay in
+    /// memory until pinning is stopped (growing the BufFile past its capacity if need be), so
+    /// the writes cannot reach the disk before they have been committed elsewhere.
+    pub fn pin_writes(&mut self, pin: bool) -> Result<(), Error> {
+        self.pin_writes = pin;
+        if pin { return Ok(()) }
+        for slab in self.dat.iter_mut() {
+            slab.pinned = false;
+        }
+        // Drop the slabs that were added past capacity while everything was pinned
+        while self.dat.len() > self.slabs {
+            let mut slab = self.dat.pop().unwrap();
+            check!(slab.write(&mut self.file, self.end));
+            self.map.remove(&slab.start);
+            self.policy.removed(self.dat.len());
+        }
+        Ok(())
+    }
+
+    /// The number of bytes in each slab.
+    pub fn slab_size(&self) -> usize {
+        self.slab_size
+    }
+
+    /// Writes every slab to disk, drops them all and uses slabs of slab_size bytes from then
+    /// on. Fails if slab_size is not a power of two, or while writes are being pinned.
+    pub fn set_slab_size(&mut self, slab_size: usize) -> Result<(), Error> {
+        if !slab_size.is_power_of_two() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                format!("slab size must be a power of two, not {}", slab_size)));
+        }
+        if self.pin_writes {
+            return Err(Error::other("can't change the slab size while writes are pinned"));
+        }
+        check!(self.flush());
+        for slot in 0..self.dat.len() {
+            self.policy.removed(slot);
+        }
+        self.dat.clear();
+        self.map.clear();
+        self.slab_size = slab_size;
+        self.slab_mask = slab_size as u64 - 1;
+        Ok(())
+    }
+
+    /// Writes every slab back to the file and closes it. Dropping a BufFile does the same, but
+    /// can't report a failure.
+    pub fn close(mut self) -> Result<(), Error> {
+        self.flush()
+    }
+
+    /// Moves the end of the file back to len, and cuts the file on disk back to it if it already
+    /// reaches past it, so the bytes past the end are not taken for data when it is reopened.
+    pub fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        self.end = len;
+        let on_disk;
+        check!(self.file.metadata(), on_disk);
+        if on_disk.len() > len {
+            check!(self.file.set_len(len));
+        }
+        Ok(())
+    }
+
+    /// Writes every slab to disk and waits for the data to reach the disk. Unlike flush, once
+    /// this returns the data survives a power loss.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        check!(self.flush());
+        self.file.sync_data()
+    }
+
+    /// Writes the buffered data in the file indices [start, end) to disk and waits for it to
+    /// reach the disk. Syncing parts of a file separately controls the order they become durable.
+    pub fn sync_range(&mut self, start: u64, end: u64) -> Result<(), Error> {
+        check!(self.flush_range(start, end));
+        self.file.sync_data()
+    }
+
+    /// Writes the buffered data in the file indices [start, end) to disk, without waiting for
+    /// it to reach the disk.
+    pub fn flush_range(&mut self, start: u64, end: u64) -> Result<(), Error> {
+        let end = cmp::min(end, self.end);
+        if start >= end { return Ok(()) }
+        let first = start & !self.slab_mask;
+        if (end - first) / self.slab_size as u64 > self.dat.len() as u64 {
+            for slab in self.dat.iter_mut() {
+                check!(slab.write_range(&mut self.file, start, end));
+            }
+        } else {
+            // Only a few slabs could hold the range, so look them up rather than trying them all
+            let mut at = first;
+            while at < end {
+                if let Some(index) = self.find_slab(at) {
+                    check!(self.dat[index].write_range(&mut self.file, start, end));
+                }
+                at += self.slab_size as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for what has already been written to disk to reach it, without writing back
+    /// anything that is buffered. Flushing ranges and then calling this orders them.
+    pub fn sync_flushed(&mut self) -> Result<(), Error> {
+        self.file.sync_data()
+    }
+
     /// Finds the slab that contains file index loc, if it doesn't exist None
     /// is returned. If it does exist, Some(index) is returned, where index
     /// is an index into self.dat.
     fn find_slab(&self, loc: u64) -> Option<usize> {
-        let start = (loc | SLAB_MASK) ^ SLAB_MASK;
+        let start = (loc | self.slab_mask) ^ self.slab_mask;
         if self.map.contains_key(&start) {
-            let x = self.map[&start].clone();
+            let x = self.map[&start];
             Some(x)
         } else {
             None
@@ -101,104 +294,120 @@ impl BufFile {
     }
 
     /// Adds a slab to the BufFile, if it isn't already present. It will write
-    /// the least frequently used slab to disk and load the new one into self.dat,
+    /// the slab chosen by the policy to disk and load the new one into self.dat,
     /// then return Ok(index), index being an index for self.dat.
     fn add_slab(&mut self, loc: u64) -> Result<usize, Error> {
-        let start = (loc | SLAB_MASK) ^ SLAB_MASK;
+        let start = (loc | self.slab_mask) ^ self.slab_mask;
         if self.map.contains_key(&start) {
-            return Ok(self.map[&start].clone());
-        }
-        // Add up to 2048 bytes if the file is not long enough for this incoming location
-        let len = self.end as usize;
-        // The end if the file is not as long as it needs to be, write some dummy data (0's) to extend it
-        // This behavior will allow some strange behavior through, but it shouldnt't really be harmful
-        if len < start as usize + SLAB_SIZE && len < loc as usize {
-            let i = vec![0; SLAB_SIZE];
-            let dif = len & SLAB_MASK as usize;
-            check!(self.file.write_all(&i[0..SLAB_SIZE - dif]));
-            self.end = loc + 1;
-        }
-        // If we're not at the maximum number of slabs, make a new one,
-        // and add it to dat and to the map
-        if self.dat.len() < self.slabs {
-            let ind = self.dat.len();
-            match Slab::new(start, &mut self.file) {
-                Ok(x) => {
-                    self.map.insert(start, self.dat.len());
-                    self.dat.push(x);
-                    Ok(ind)
-                },
-                Err(e) => Err(e)
-            }
+            return Ok(self.map[&start]);
         }
+        // A slab past the end of the file simply reads as zeros; writing to it moves the end
+        let slab;
+        check!(Slab::new(start, self.slab_size, &mut self.file), slab);
 
-        // We are at the maximum number of slabs - one of them must be removed
-        else {
-            // Find the minimum - we have to go through all of them, there isn't
-            // a simple solution to avoid this that can easily be implemented.
-            // (maybe fibonacci heap?)
-            let mut min = 0;
-            for i in 0..self.slabs {
-                if self.dat[min].uses == 1 {
-                    min = i;
-                    // The minimum number of reads is 1, so if we encounter 1 just break.
-                    break;
-                }
-                if self.dat[min].uses > self.dat[i].uses {
-                    min = i;
-                }
+        // If we're not at the maximum number of slabs, add the new one to dat and to the map
+        let victim = if self.dat.len() < self.slabs {
+            None
+        } else {
+            // We are at the maximum number of slabs - one of them must be removed.
+            // Pinned slabs can't be evicted.
+            let dat = &self.dat;
+            self.policy.evict(start, &|i| dat[i].pinned)
+        };
+
+        let ind = match victim {
+            // Write the old slab to disk, then replace it
+            Some(ind) => {
+                check!(self.dat[ind].write(&mut self.file, self.end));
+                self.map.remove(&self.dat[ind].start);
+                self.dat[ind] = slab;
+                ind
+            },
+            // There is room, or everything is pinned and we have to go past capacity
+            None => {
+                self.dat.push(slab);
+                self.dat.len() - 1
             }
-            // Make a new slab, write the old one to disk, replace old slab
-            match Slab::new(start, &mut self.file) {
-                Ok(x) => {
-                    // Write the old slab to disk
-                    check!(self.dat[min].write(&mut self.file));
-
-                    // Move the cursor back to where it was
-                    self.file.seek(SeekFrom::Start(self.cursor));
+        };
+        self.map.insert(start, ind);
+        self.policy.loaded(ind, start);
+        Ok(ind)
+    }
+}
 
-                    // Remove the old slab from the map
-                    self.map.remove(&self.dat[min].start);
+/// Reads the file of a BufFile through a handle of its own, without going through the slabs, so
+/// many threads can read at once. Only what the BufFile has written back is seen.
+pub struct FileReader {
+    file: File,
+    /// The end of the file, as of when the BufFile was last published.
+    pub end: u64
+}
 
-                    // Add the new one
-                    self.map.insert(start, min);
+impl FileReader {
+    pub fn new(file: &BufFile) -> Result<FileReader, Error> {
+        let clone;
+        check!(file.file.try_clone(), clone);
+        Ok(FileReader { file: clone, end: file.end })
+    }
+}
 
-                    // Assign the new value
-                    self.dat[min] = x;
-                    Ok(min)
-                },
-                Err(x) => Err(x)
-            }
+impl SharedRead for FileReader {
+    fn read_shared(&self, pos: u64, buf: &mut [u8]) -> Result<(), Error> {
+        // Only the bytes before the end are read, since the file may hold old bytes past it
+        let len = cmp::min(self.end.saturating_sub(pos), buf.len() as u64) as usize;
+        let mut done = 0;
+        while done < len {
+            let n;
+            check!(read_file_at(&self.file, pos + done as u64, &mut buf[done .. len]), n);
+            if n == 0 { break }
+            done += n;
         }
+        for b in buf[done ..].iter_mut() { *b = 0; }
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.end
     }
 }
 
+/// Reads from file at pos without moving its cursor, returning the number of bytes read.
+#[cfg(unix)]
+fn read_file_at(file: &File, pos: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, pos)
+}
+
+/// Reads from file at pos, returning the number of bytes read. The cursor moves, but BufFile
+/// always seeks before using it.
+#[cfg(windows)]
+fn read_file_at(file: &File, pos: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, pos)
+}
+
 impl Read for BufFile {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
         // If the place the cursor will be after the read is in the same slab as it will be during the beginning,
-        // and the length of the buffer is less than SLAB_SIZE
-        if buf.len() <= SLAB_SIZE
-            && (((buf.len() as u64 + self.cursor - 1) | SLAB_MASK) ^ SLAB_MASK == (self.cursor | SLAB_MASK) ^ SLAB_MASK)
+        // and the length of the buffer is less than the slab size
+        if buf.len() <= self.slab_size
+            && (((buf.len() as u64 + self.cursor - 1) | self.slab_mask) ^ self.slab_mask == (self.cursor | self.slab_mask) ^ self.slab_mask)
             {
-            // The index in self.dat (which slab to use)
-            let index;
             let cursor = self.cursor;
 
-            // If we dont find it, add a new one!
-            match self.find_slab(cursor) {
-                Some(x) => index = x,
-                None => match self.add_slab(cursor) {
-                    Ok(x) => index = x,
-                    Err(e) => return Err(e)
-                }
+            // The index in self.dat (which slab to use). If we dont find it, add a new one!
+            let index = match self.find_slab(cursor) {
+                Some(x) => {
+                    // We're using this slab again
+                    self.policy.accessed(x);
+                    x
+                },
+                None => self.add_slab(cursor)?
             };
-
-            // We're using this slab, so increment its use count
-            self.dat[index].uses += 1;
             {
                 // Since we're indexing, only use the lower bits n as index.
-                let masked = (self.cursor & SLAB_MASK) as usize;
-                let mut slice = &mut self.dat[index].dat[masked as usize .. masked as usize + buf.len()];
+                let masked = (self.cursor & self.slab_mask) as usize;
+                let slice = &self.dat[index].dat[masked .. masked + buf.len()];
                 buf.clone_from_slice(slice);
             }
 
@@ -208,41 +417,32 @@ impl Read for BufFile {
             // Return the number of bytes read
             Ok(buf.len())
         }
-        // the data is contained in more than one slab, and may be larger than SLAB_SIZE bytes
+        // the data is contained in more than one slab, and may be larger than the slab size bytes
         else {
-            // How many times does SLAB_SIZE go into slabs? Thats the lower limit on how many slabs we have to read from
-            let mut slabs = buf.len() / SLAB_SIZE;
-            // If there is a remainder
-            if buf.len() as u64 & SLAB_MASK > 0 { slabs += 1; }
-            // There is less than SLAB_SIZE bytes to read, but it is spread out over 2 slabs
-            if slabs == 1 { slabs = 2; }
-
             let mut bytes_read = 0;
-            // For each slab we have to go through
-            for _ in 0..slabs {
+            // For each slab we have to go through. An unaligned buffer can touch one more slab
+            // than its length alone suggests, so go until everything is done.
+            while bytes_read < buf.len() {
                 // How many bytes to we have to read this iteration? Either the rest of the data or the rest of a slab
-                let mut to_read = cmp::min(SLAB_SIZE - (self.cursor & SLAB_MASK) as usize, buf.len() - bytes_read);
-                // if cursor is a multiple of SLAB_SIZE then cursor & slab_mask will be 0
+                let to_read = cmp::min(self.slab_size - (self.cursor & self.slab_mask) as usize, buf.len() - bytes_read);
+                // if cursor is a multiple of the slab size then cursor & slab_mask will be 0
 
                 // Which slab to read from
-                let index;
                 // Combat the borrow-checker
                 let cursor = self.cursor;
                 // If our slab is already present, cool, if not, add it
-                match self.find_slab(cursor) {
-                    Some(x) => index = x,
-                    None =>
-                        match self.add_slab(cursor) {
-                            Ok(x) => index = x,
-                            Err(e) => return Err(e)
-                        }
+                let index = match self.find_slab(cursor) {
+                    Some(x) => {
+                        // We're using this slab again
+                        self.policy.accessed(x);
+                        x
+                    },
+                    None => self.add_slab(cursor)?
                 };
-                // We're using the slab so increment the use count
-                self.dat[index].uses += 1;
                 {
-                    let masked = (self.cursor & SLAB_MASK) as usize;
-                    let mut slice = &mut self.dat[index].dat[masked as usize .. masked as usize + to_read];
-                    let mut target = &mut buf[bytes_read .. bytes_read + to_read];
+                    let masked = (self.cursor & self.slab_mask) as usize;
+                    let slice = &self.dat[index].dat[masked .. masked + to_read];
+                    let target = &mut buf[bytes_read .. bytes_read + to_read];
                     target.clone_from_slice(slice);
                 }
                 self.cursor += to_read as u64;
@@ -257,77 +457,75 @@ impl Read for BufFile {
 impl Write for BufFile {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
         // If the place the cursor will be after the write is in the same slab as it will be during the beginning,
-        // and the length of the buffer is less than SLAB_SIZE
-        if buf.len() <= SLAB_SIZE
-        && (((buf.len() as u64 + self.cursor - 1) | SLAB_MASK) ^ SLAB_MASK == (self.cursor | SLAB_MASK) ^ SLAB_MASK)
+        // and the length of the buffer is less than the slab size
+        if buf.len() <= self.slab_size
+        && (((buf.len() as u64 + self.cursor - 1) | self.slab_mask) ^ self.slab_mask == (self.cursor | self.slab_mask) ^ self.slab_mask)
         {
-            // The index in self.dat (which slab to use)
-            let index;
             let cursor = self.cursor;
 
-            // If we dont find it, add a new one!
-            match self.find_slab(cursor) {
-                Some(x) => index = x,
-                None => match self.add_slab(cursor) {
-                    Ok(x) => index = x,
-                    Err(e) => return Err(e)
-                }
+            // The index in self.dat (which slab to use). If we dont find it, add a new one!
+            let index = match self.find_slab(cursor) {
+                Some(x) => {
+                    // We're using this slab again
+                    self.policy.accessed(x);
+                    x
+                },
+                None => self.add_slab(cursor)?
             };
-
-            // We're using this slab, so increment its use count
-            self.dat[index].uses += 1;
+            if self.pin_writes { self.dat[index].pinned = true; }
             {
                 // Since we're indexing, only use the lower bits n as index.
-                let masked = (self.cursor & SLAB_MASK) as usize;
-                let mut slice = &mut self.dat[index].dat[masked as usize .. masked as usize + buf.len()];
+                let masked = (self.cursor & self.slab_mask) as usize;
+                let slice = &mut self.dat[index].dat[masked .. masked + buf.len()];
                 slice.clone_from_slice(buf);
             }
+            let masked = (self.cursor & self.slab_mask) as usize;
+            self.dat[index].mark_dirty(masked, masked + buf.len());
 
             // Move the cursor
             self.cursor += buf.len() as u64;
+            // Writing past the end grows the file
+            if self.cursor > self.end { self.end = self.cursor; }
 
             // Return the number of bytes written
             Ok(buf.len())
         }
-        // the data is contained in more than one slab, and may be larger than SLAB_SIZE bytes
+        // the data is contained in more than one slab, and may be larger than the slab size bytes
         else {
-            // How many times does SLAB_SIZE go into slabs? Thats the lower limit on how many slabs we have to read from
-            let mut slabs = buf.len() / SLAB_SIZE;
-            // If there is a remainder
-            if buf.len() as u64 & SLAB_MASK > 0 { slabs += 1; }
-            // There is less than SLAB_SIZE bytes to read, but it is spread out over 2 slabs
-            if slabs == 1 { slabs = 2; }
-
             let mut bytes_written = 0;
-            // For each slab we have to go through
-            for _ in 0..slabs {
+            // For each slab we have to go through. An unaligned buffer can touch one more slab
+            // than its length alone suggests, so go until everything is done.
+            while bytes_written < buf.len() {
                 // How many bytes to we have to read this iteration? Either the rest of the data or the rest of a slab
-                let mut to_write = cmp::min(SLAB_SIZE - (self.cursor & SLAB_MASK) as usize, buf.len() - bytes_written);
-                // if cursor is a multiple of SLAB_SIZE then cursor & slab_mask will be 0
+                let to_write = cmp::min(self.slab_size - (self.cursor & self.slab_mask) as usize, buf.len() - bytes_written);
+                // if cursor is a multiple of the slab size then cursor & slab_mask will be 0
 
                 // Which slab to read from
-                let index;
                 // Combat the borrow-checker
                 let cursor = self.cursor;
                 // If our slab is already present, cool, if not, add it
-                match self.find_slab(cursor) {
-                    Some(x) => index = x,
-                    None =>
-                        match self.add_slab(cursor) {
-                            Ok(x) => index = x,
-                            Err(e) => return Err(e)
-                        }
+                let index = match self.find_slab(cursor) {
+                    Some(x) => {
+                        // We're using this slab again
+                        self.policy.accessed(x);
+                        x
+                    },
+                    None => self.add_slab(cursor)?
                 };
-                // We're using the slab so increment the use count
-                self.dat[index].uses += 1;
+                if self.pin_writes { self.dat[index].pinned = true; }
                 {
-                    let masked = (self.cursor & SLAB_MASK) as usize;
-                    let mut slice = &mut self.dat[index].dat[masked as usize .. masked as usize + to_write];
-                    let mut target = &buf[bytes_written .. bytes_written + to_write];
+                    let masked = (self.cursor & self.slab_mask) as usize;
+                    let slice = &mut self.dat[index].dat[masked .. masked + to_write];
+                    let target = &buf[bytes_written .. bytes_written + to_write];
                     slice.clone_from_slice(target);
                 }
+                let masked = (self.cursor & self.slab_mask) as usize;
+                self.dat[index].mark_dirty(masked, masked + to_write);
                 self.cursor += to_write as u64;
                 bytes_written += to_write;
+                // Writing past the end grows the file. This must happen slab by slab, since
+                // loading the next slab may evict this one, and evictions stop at the end.
+                if self.cursor > self.end { self.end = self.cursor; }
             }
 
             Ok(buf.len())
@@ -335,65 +533,35 @@ impl Write for BufFile {
     }
 
     fn flush(&mut self) -> Result<(), Error> {
-        for slab in self.dat.iter() {
-            check!(slab.write(&mut self.file))
+        for slab in self.dat.iter_mut() {
+            check!(slab.write(&mut self.file, self.end))
         }
         Ok(())
     }
 }
 
 impl Seek for BufFile {
+    /// Moves the cursor. Slabs are only loaded once they are read from or written to, so
+    /// seeking never touches the file.
     fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
-        match pos {
-            SeekFrom::Start(x) => {
-                if self.find_slab(x).is_none() {
-                    let cursor = self.cursor;
-                    match self.add_slab(cursor) {
-                        Ok(_) => {},
-                        Err(e) => return Err(e)
-                    }
-                }
-                self.cursor = x;
-                Ok(self.cursor)
-            },
-            SeekFrom::End(x) => {
-                self.cursor =
-                    if x < 0 { self.end - (-x) as u64 }     // This would be an error if buffers / files
-                    else { self.end - x as u64 };           // weren't automatically extended beyond
-                                                            // the end.
-                let cursor = self.cursor;
-                if self.find_slab(cursor).is_none() {
-                    match self.add_slab(cursor) {
-                        Ok(_) => {},
-                        Err(e) => return Err(e)
-                    }
-                }
-
-                Ok(cursor)
-            },
-            SeekFrom::Current(x) => {
-                let cur = self.cursor;
-
-                let cursor =
-                    if x < 0 { cur - (-x) as u64 }
-                    else { cur - x as u64 };
-                self.cursor = cursor;
-
-                if self.find_slab(cursor).is_none() {
-                    match self.add_slab(cursor) {
-                        Ok(_) => {},
-                        Err(e) => return Err(e)
-                    }
-                }
-
-                Ok(self.cursor)
-            }
-        }
+        self.cursor = match pos {
+            SeekFrom::Start(x) => x,
+            SeekFrom::End(x) =>
+                if x < 0 { self.end - (-x) as u64 }     // Seeking past the end is fine, since
+                else { self.end + x as u64 },           // buffers / files are automatically
+                                                        // extended beyond the end.
+            SeekFrom::Current(x) =>
+                if x < 0 { self.cursor - (-x) as u64 }
+                else { self.cursor + x as u64 }
+        };
+        Ok(self.cursor)
     }
 }
 
 impl Drop for BufFile {
-     fn drop(&mut self) {
-         self.flush();
-     }
+    fn drop(&mut self) {
+        // Drop can't report an error. Callers that need to know whether everything was written
+        // back use close instead.
+        let _ = self.flush();
+    }
 }
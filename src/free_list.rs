@@ -0,0 +1,91 @@
+use std::cmp;
+use std::io::{ Error, Read, Write };
+use raw_serde::*;
+use btree::NONE;
+
+/// The number of size classes kept for the key file and for the value file.
+pub const NUM_CLASSES: usize = 64;
+
+/// The number of classes that hold blocks of one exact size: MIN_RECORD, MIN_RECORD + 1 and so on.
+pub const EXACT_CLASSES: usize = 40;
+
+/// Every key and value takes up a multiple of this many bytes, and at least this many, so that a
+/// free block can always hold the location of the next one. What is left of a free block once a
+/// record has been cut from it is then either nothing or large enough to be freed in turn.
+pub const MIN_RECORD: u64 = 8;
+
+/// Blocks smaller than this only hold the location of the next block, their size being implied by
+/// their class. Larger blocks hold the location of the next block followed by their size.
+pub const MIN_SIZED_BLOCK: u64 = 16;
+
+/// The heads of the lists of free space in the three files of a tree, NONE for an empty list.
+///
+/// Free nodes all have the same size, so they share one list. Free space in the key and value
+/// files is kept in size classes: the first EXACT_CLASSES classes hold blocks of one size each,
+/// and each class after that holds blocks at least twice as large as the one before it, the last
+/// class holding everything that is larger still. Every block is a multiple of MIN_RECORD bytes,
+/// so no part of a block is lost by splitting it. Adjacent blocks are not merged as they are
+/// freed, but a tree merges them all when no block is large enough for a record.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FreeLists {
+    pub nodes: u64,
+    pub keys: Vec<u64>,
+    pub values: Vec<u64>
+}
+
+impl FreeLists {
+    pub fn new() -> FreeLists {
+        FreeLists {
+            nodes: NONE,
+            keys: vec![NONE; NUM_CLASSES],
+            values: vec![NONE; NUM_CLASSES]
+        }
+    }
+}
+
+/// The lists are written without lengths, since there are always NUM_CLASSES of them.
+impl RawSerialize for FreeLists {
+    fn raw_serialize(&self, to: &mut dyn Write) -> Result<u64, Error> {
+        check!(self.nodes.raw_serialize(to));
+        for head in self.keys.iter().chain(self.values.iter()) {
+            check!(head.raw_serialize(to));
+        }
+        Ok(8 + 16 * NUM_CLASSES as u64)
+    }
+}
+
+impl RawDeserialize for FreeLists {
+    fn raw_deserialize(from: &mut dyn Read) -> Result<FreeLists, Error> {
+        let mut free = FreeLists::new();
+        let nodes;
+        check!(u64::raw_deserialize(from), nodes);
+        free.nodes = nodes;
+        for i in 0 .. 2 * NUM_CLASSES {
+            let head;
+            check!(u64::raw_deserialize(from), head);
+            if i < NUM_CLASSES { free.keys[i] = head; } else { free.values[i - NUM_CLASSES] = head; }
+        }
+        Ok(free)
+    }
+}
+
+/// The number of bytes a key or value that serializes to len bytes takes up in its file.
+pub fn record_size(len: u64) -> u64 {
+    cmp::max(len.div_ceil(MIN_RECORD), 1) * MIN_RECORD
+}
+
+/// The class a free block of size bytes belongs in. size must be at least MIN_RECORD.
+pub fn class_of(size: u64) -> usize {
+    let first_ranged = MIN_RECORD + EXACT_CLASSES as u64;
+    if size < first_ranged {
+        (size - MIN_RECORD) as usize
+    } else {
+        let doublings = 63 - (size / first_ranged).leading_zeros() as usize;
+        if EXACT_CLASSES + doublings < NUM_CLASSES { EXACT_CLASSES + doublings } else { NUM_CLASSES - 1 }
+    }
+}
+
+/// The size of every block in the class, if they all have the same size.
+pub fn exact_size(class: usize) -> Option<u64> {
+    if class < EXACT_CLASSES { Some(MIN_RECORD + class as u64) } else { None }
+}
@@ -0,0 +1,109 @@
+use std::io::{ Error, ErrorKind, Read };
+use raw_serde::*;
+use btree::{ MIN_DEGREE, MAX_DEGREE };
+use wal::checksum;
+use free_list::FreeLists;
+use inline_key::MAX_INLINE_KEY_SIZE;
+use type_tag::TypeTag;
+
+/// Identifies a treefile. Always the first 8 bytes of it.
+pub const MAGIC: [u8; 8] = *b"PBTREE\0\0";
+
+/// The version of the on disk format written by this crate. Bump it whenever the layout of the
+/// header, nodes, keys or values changes.
+pub const FORMAT_VERSION: u64 = 5;
+
+/// Bytes reserved for the header at the start of the treefile. The first node comes right
+/// after. Only the beginning is used; the rest is zeroed and left for later versions.
+pub const HEADER_SIZE: u64 = 4096;
+
+/// The first thing in every treefile.
+#[derive(RawSerialize, RawDeserialize, Clone, Debug)]
+pub struct Header {
+    pub magic: [u8; 8],
+    pub version: u64,
+    pub root_location: u64,
+    /// The minimum degree of the tree.
+    pub degree: u64,
+    /// Fingerprints of the key and value types the tree was created with.
+    pub key_type: u64,
+    pub value_type: u64,
+    /// The size of the keys stored in the nodes, or 0 if the nodes hold the locations of keys in
+    /// the key file.
+    pub inline_key_size: u64,
+    /// The number of entries in the tree.
+    pub len: u64,
+    /// The free space in the tree, key and value files.
+    pub free: FreeLists
+}
+
+impl Header {
+    /// The header of a new tree with keys of type K and values of type V.
+    pub fn new<K: TypeTag, V: TypeTag>(root_location: u64, degree: u64, inline_key_size: u64, len: u64, free: FreeLists) -> Header {
+        Header {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            root_location,
+            degree,
+            key_type: fingerprint::<K>(),
+            value_type: fingerprint::<V>(),
+            inline_key_size,
+            len,
+            free
+        }
+    }
+
+    /// Reads the header of the treefile at path, making sure it belongs to a tree this crate can
+    /// open with keys of type K and values of type V.
+    pub fn read<K: TypeTag, V: TypeTag>(from: &mut dyn Read, path: &str) -> Result<Header, Error> {
+        let header;
+        check!(Header::raw_deserialize(from), header);
+        if header.magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{}.tree is not a tree file", path)));
+        }
+        if header.version != FORMAT_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{}.tree has format version {}, but only version {} is supported", path, header.version, FORMAT_VERSION)));
+        }
+        if header.degree < MIN_DEGREE || header.degree > MAX_DEGREE {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{}.tree records an invalid minimum degree {}", path, header.degree)));
+        }
+        if header.inline_key_size > MAX_INLINE_KEY_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{}.tree records an invalid inline key size {}", path, header.inline_key_size)));
+        }
+        if !fingerprints_match(header.key_type, fingerprint::<K>()) {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{}.tree was not created with keys of type {}", path, K::type_tag())));
+        }
+        if !fingerprints_match(header.value_type, fingerprint::<V>()) {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{}.tree was not created with values of type {}", path, V::type_tag())));
+        }
+        Ok(header)
+    }
+
+    /// The header followed by zeros up to HEADER_SIZE.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        // Writing to a Vec can't fail
+        let _ = self.raw_serialize(&mut buf);
+        buf.resize(HEADER_SIZE as usize, 0);
+        buf
+    }
+}
+
+/// A hash of the tag of type T. It depends only on the tag, so it is the same whichever compiler
+/// built the crate, and tells types apart well enough to catch a tree being opened with the wrong
+/// types. A type with an empty tag opts out of the check, and has a fingerprint of 0.
+pub fn fingerprint<T: TypeTag>() -> u64 {
+    let tag = T::type_tag();
+    if tag.is_empty() { 0 } else { checksum(tag.as_bytes()) }
+}
+
+/// Whether a tree whose header records the fingerprint recorded can be opened with a type whose
+/// fingerprint is expected. Either being 0 skips the check.
+fn fingerprints_match(recorded: u64, expected: u64) -> bool {
+    recorded == 0 || expected == 0 || recorded == expected
+}
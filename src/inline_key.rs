@@ -0,0 +1,25 @@
+use raw_serde::*;
+
+/// The largest key, in bytes, that can be stored inline.
+pub const MAX_INLINE_KEY_SIZE: u64 = 256;
+
+/// Keys that always serialize to SIZE bytes. A tree created with PBTree::with_inline_keys keeps
+/// such keys in its nodes rather than in the key file, so comparing a key against those in a node
+/// takes no reads beyond that of the node.
+///
+/// Implementing this for a type whose serialized size varies corrupts any tree created with it.
+pub trait InlineKey: RawSerialize + RawDeserialize {
+    const SIZE: u64;
+}
+
+macro_rules! inline_key {
+    ($($t:ty, $size:expr);*) => {
+        $(impl InlineKey for $t { const SIZE: u64 = $size; })*
+    }
+}
+
+inline_key!(u8, 1; u16, 2; u32, 4; u64, 8; u128, 16; i8, 1; i16, 2; i32, 4; i64, 8; i128, 16);
+inline_key!([u8; 1], 1; [u8; 2], 2; [u8; 3], 3; [u8; 4], 4; [u8; 5], 5; [u8; 6], 6; [u8; 7], 7; [u8; 8], 8);
+inline_key!([u8; 9], 9; [u8; 10], 10; [u8; 11], 11; [u8; 12], 12; [u8; 13], 13; [u8; 14], 14; [u8; 15], 15; [u8; 16], 16);
+inline_key!([u8; 17], 17; [u8; 18], 18; [u8; 19], 19; [u8; 20], 20; [u8; 21], 21; [u8; 22], 22; [u8; 23], 23; [u8; 24], 24);
+inline_key!([u8; 25], 25; [u8; 26], 26; [u8; 27], 27; [u8; 28], 28; [u8; 29], 29; [u8; 30], 30; [u8; 31], 31; [u8; 32], 32);
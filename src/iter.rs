@@ -0,0 +1,392 @@
+use std::io::Error;
+use std::fmt::Debug;
+use std::ops::Bound;
+use raw_serde::*;
+use type_tag::TypeTag;
+use btree::PBTree;
+use node::Node;
+use file_buffer::BufFile;
+use storage::Storage;
+
+/// Walks the entries of a PBTree in key order, producing the key slot and value location of each.
+/// Nodes are fetched through the tree's NodeCache as they are reached, so only the paths from
+/// the root to the current entries are ever held in memory. The walk can proceed from both ends,
+/// and stops once the two meet.
+struct Cursor {
+    /// The path to the front: each node, and the index of the next key to yield from it.
+    front: Vec<(Node, usize)>,
+    /// The path to the back: each node, and one past the index of the next key to yield from it.
+    back: Vec<(Node, usize)>,
+    /// The node location and index of the entry most recently yielded from the front.
+    front_last: Option<(u64, usize)>,
+    /// The node location and index of the entry most recently yielded from the back.
+    back_last: Option<(u64, usize)>,
+    front_started: bool,
+    back_started: bool,
+    done: bool
+}
+
+impl Cursor {
+    fn new() -> Cursor {
+        Cursor {
+            front: vec![],
+            back: vec![],
+            front_last: None,
+            back_last: None,
+            front_started: false,
+            back_started: false,
+            done: false
+        }
+    }
+
+    /// Pushes the node at loc, and the leftmost path beneath it, onto the front stack.
+    fn push_leftmost<K, V, S: Storage>(&mut self, tree: &mut PBTree<K, V, S>, mut loc: u64) -> Result<(), Error>
+        where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+                V: RawSerialize + RawDeserialize + TypeTag + Debug {
+        loop {
+            let node;
+            check!(tree.node(loc), node);
+            let (leaf, child) = (node.leaf, node.children[0]);
+            self.front.push((node, 0));
+            if leaf { return Ok(()) }
+            loc = child;
+        }
+    }
+
+    /// Pushes the node at loc, and the rightmost path beneath it, onto the back stack.
+    fn push_rightmost<K, V, S: Storage>(&mut self, tree: &mut PBTree<K, V, S>, mut loc: u64) -> Result<(), Error>
+        where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+                V: RawSerialize + RawDeserialize + TypeTag + Debug {
+        loop {
+            let node;
+            check!(tree.node(loc), node);
+            let len = node.len as usize;
+            let (leaf, child) = (node.leaf, node.children[len]);
+            self.back.push((node, len));
+            if leaf { return Ok(()) }
+            loc = child;
+        }
+    }
+
+    /// Positions the front at the first entry that lies within the lower bound.
+    fn seek_front<K, V, S: Storage>(&mut self, tree: &mut PBTree<K, V, S>, lo: Bound<&K>) -> Result<(), Error>
+        where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+                V: RawSerialize + RawDeserialize + TypeTag + Debug {
+        self.front_started = true;
+        let mut loc = tree.root_location;
+        loop {
+            let node;
+            check!(tree.node(loc), node);
+            let i = match lo {
+                Bound::Included(k) => {
+                    let found;
+                    check!(tree.locate(&node, k), found);
+                    found.0
+                },
+                Bound::Excluded(k) => {
+                    let found;
+                    check!(tree.locate(&node, k), found);
+                    if found.1 { found.0 + 1 } else { found.0 }
+                },
+                Bound::Unbounded => 0
+            };
+            let (leaf, child) = (node.leaf, node.children[i]);
+            self.front.push((node, i));
+            if leaf { return Ok(()) }
+            loc = child;
+        }
+    }
+
+    /// Positions the back at the last entry that lies within the upper bound.
+    fn seek_back<K, V, S: Storage>(&mut self, tree: &mut PBTree<K, V, S>, hi: Bound<&K>) -> Result<(), Error>
+        where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+                V: RawSerialize + RawDeserialize + TypeTag + Debug {
+        self.back_started = true;
+        let mut loc = tree.root_location;
+        loop {
+            let node;
+            check!(tree.node(loc), node);
+            let i = match hi {
+                Bound::Included(k) => {
+                    let found;
+                    check!(tree.locate(&node, k), found);
+                    if found.1 { found.0 + 1 } else { found.0 }
+                },
+                Bound::Excluded(k) => {
+                    let found;
+                    check!(tree.locate(&node, k), found);
+                    found.0
+                },
+                Bound::Unbounded => node.len as usize
+            };
+            let (leaf, child) = (node.leaf, node.children[i]);
+            self.back.push((node, i));
+            if leaf { return Ok(()) }
+            loc = child;
+        }
+    }
+
+    fn next<K, V, S: Storage>(&mut self, tree: &mut PBTree<K, V, S>) -> Option<Result<(Vec<u8>, u64), Error>>
+        where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+                V: RawSerialize + RawDeserialize + TypeTag + Debug {
+        if self.done { return None }
+        if !self.front_started {
+            self.front_started = true;
+            let root = tree.root_location;
+            if let Err(e) = self.push_leftmost(tree, root) { return self.fail(e) }
+        }
+        loop {
+            // The location of the top node, and its next entry and the subtree after it if any
+            let (loc, i, entry) = match self.front.last_mut() {
+                Some(&mut (ref node, ref mut i)) => {
+                    let current = *i;
+                    *i += 1;
+                    let entry = if current < node.len as usize {
+                        let right = if node.leaf { None } else { Some(node.children[current + 1]) };
+                        Some((node.key(current).to_vec(), node.values[current], right))
+                    } else {
+                        None
+                    };
+                    (node.loc, current, entry)
+                },
+                None => return self.finish()
+            };
+            if let Some((k, v, right)) = entry {
+                if self.back_last == Some((loc, i)) { return self.finish() }
+                self.front_last = Some((loc, i));
+                // Everything in the subtree right of this key comes before the next key
+                if let Some(right) = right {
+                    if let Err(e) = self.push_leftmost(tree, right) { return self.fail(e) }
+                }
+                return Some(Ok((k, v)))
+            }
+            self.front.pop();
+        }
+    }
+
+    fn next_back<K, V, S: Storage>(&mut self, tree: &mut PBTree<K, V, S>) -> Option<Result<(Vec<u8>, u64), Error>>
+        where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+                V: RawSerialize + RawDeserialize + TypeTag + Debug {
+        if self.done { return None }
+        if !self.back_started {
+            self.back_started = true;
+            let root = tree.root_location;
+            if let Err(e) = self.push_rightmost(tree, root) { return self.fail(e) }
+        }
+        loop {
+            // The location of the top node, and its previous entry and the subtree before it if any
+            let (loc, i, entry) = match self.back.last_mut() {
+                Some(&mut (ref node, ref mut i)) => {
+                    if *i == 0 {
+                        (node.loc, 0, None)
+                    } else {
+                        *i -= 1;
+                        let left = if node.leaf { None } else { Some(node.children[*i]) };
+                        (node.loc, *i, Some((node.key(*i).to_vec(), node.values[*i], left)))
+                    }
+                },
+                None => return self.finish()
+            };
+            if let Some((k, v, left)) = entry {
+                if self.front_last == Some((loc, i)) { return self.finish() }
+                self.back_last = Some((loc, i));
+                // Everything in the subtree left of this key comes after the next key
+                if let Some(left) = left {
+                    if let Err(e) = self.push_rightmost(tree, left) { return self.fail(e) }
+                }
+                return Some(Ok((k, v)))
+            }
+            self.back.pop();
+        }
+    }
+
+    /// Ends the walk, e.g. because the two ends met or a bound was passed.
+    fn finish<T>(&mut self) -> Option<Result<T, Error>> {
+        self.done = true;
+        self.front.clear();
+        self.back.clear();
+        None
+    }
+
+    /// Stops the walk after an error, so it is only reported once.
+    fn fail<T>(&mut self, e: Error) -> Option<Result<T, Error>> {
+        self.finish::<T>();
+        Some(Err(e))
+    }
+}
+
+/// An iterator over the entries of a PBTree, in key order.
+pub struct Iter<'a, K: 'a, V: 'a, S: 'a = BufFile> {
+    tree: &'a mut PBTree<K, V, S>,
+    cursor: Cursor
+}
+
+impl<'a, K, V, S: Storage> Iter<'a, K, V, S> {
+    pub(crate) fn new(tree: &'a mut PBTree<K, V, S>) -> Self {
+        Iter { tree, cursor: Cursor::new() }
+    }
+}
+
+impl<'a, K, V, S: Storage> Iterator for Iter<'a, K, V, S>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
+    type Item = Result<(K, V), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.cursor.next(self.tree) {
+            Some(Ok((key, v_loc))) => {
+                let k = match self.tree.decode_key(&key) {
+                    Ok(k) => k,
+                    Err(e) => return self.cursor.fail(e)
+                };
+                match self.tree.read_value(v_loc) {
+                    Ok(v) => Some(Ok((k, v))),
+                    Err(e) => self.cursor.fail(e)
+                }
+            },
+            Some(Err(e)) => Some(Err(e)),
+            None => None
+        }
+    }
+}
+
+/// An iterator over the keys of a PBTree, in order. Values are never read.
+pub struct Keys<'a, K: 'a, V: 'a, S: 'a = BufFile> {
+    tree: &'a mut PBTree<K, V, S>,
+    cursor: Cursor
+}
+
+impl<'a, K, V, S: Storage> Keys<'a, K, V, S> {
+    pub(crate) fn new(tree: &'a mut PBTree<K, V, S>) -> Self {
+        Keys { tree, cursor: Cursor::new() }
+    }
+}
+
+impl<'a, K, V, S: Storage> Iterator for Keys<'a, K, V, S>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
+    type Item = Result<K, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.cursor.next(self.tree) {
+            Some(Ok((key, _))) => match self.tree.decode_key(&key) {
+                Ok(k) => Some(Ok(k)),
+                Err(e) => self.cursor.fail(e)
+            },
+            Some(Err(e)) => Some(Err(e)),
+            None => None
+        }
+    }
+}
+
+/// An iterator over the values of a PBTree, in the order of their keys. Keys are never read.
+pub struct Values<'a, K: 'a, V: 'a, S: 'a = BufFile> {
+    tree: &'a mut PBTree<K, V, S>,
+    cursor: Cursor
+}
+
+impl<'a, K, V, S: Storage> Values<'a, K, V, S> {
+    pub(crate) fn new(tree: &'a mut PBTree<K, V, S>) -> Self {
+        Values { tree, cursor: Cursor::new() }
+    }
+}
+
+impl<'a, K, V, S: Storage> Iterator for Values<'a, K, V, S>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
+    type Item = Result<V, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.cursor.next(self.tree) {
+            Some(Ok((_, v_loc))) => match self.tree.read_value(v_loc) {
+                Ok(v) => Some(Ok(v)),
+                Err(e) => self.cursor.fail(e)
+            },
+            Some(Err(e)) => Some(Err(e)),
+            None => None
+        }
+    }
+}
+
+/// An iterator over the entries of a PBTree whose keys lie within a range, in key order.
+/// Either end is positioned by descending from the root the first time it is used.
+pub struct Range<'a, K: 'a, V: 'a, S: 'a = BufFile> {
+    tree: &'a mut PBTree<K, V, S>,
+    cursor: Cursor,
+    start: Bound<K>,
+    end: Bound<K>
+}
+
+impl<'a, K, V, S: Storage> Range<'a, K, V, S> {
+    pub(crate) fn new(tree: &'a mut PBTree<K, V, S>, start: Bound<K>, end: Bound<K>) -> Self {
+        Range { tree, cursor: Cursor::new(), start, end }
+    }
+}
+
+impl<'a, K, V, S: Storage> Range<'a, K, V, S>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
+    /// Reads the entry with the given key slot and value location, ending the walk instead if the key is out of range.
+    fn read_entry(&mut self, key: Vec<u8>, v_loc: u64) -> Option<Result<(K, V), Error>> {
+        let k = match self.tree.decode_key(&key) {
+            Ok(k) => k,
+            Err(e) => return self.cursor.fail(e)
+        };
+        let in_range = (match self.start {
+            Bound::Included(ref lo) => k >= *lo,
+            Bound::Excluded(ref lo) => k > *lo,
+            Bound::Unbounded => true
+        }) && (match self.end {
+            Bound::Included(ref hi) => k <= *hi,
+            Bound::Excluded(ref hi) => k < *hi,
+            Bound::Unbounded => true
+        });
+        if !in_range { return self.cursor.finish() }
+        match self.tree.read_value(v_loc) {
+            Ok(v) => Some(Ok((k, v))),
+            Err(e) => self.cursor.fail(e)
+        }
+    }
+}
+
+impl<'a, K, V, S: Storage> Iterator for Range<'a, K, V, S>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
+    type Item = Result<(K, V), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.cursor.front_started {
+            let lo = match self.start {
+                Bound::Included(ref k) => Bound::Included(k),
+                Bound::Excluded(ref k) => Bound::Excluded(k),
+                Bound::Unbounded => Bound::Unbounded
+            };
+            if let Err(e) = self.cursor.seek_front(self.tree, lo) { return self.cursor.fail(e) }
+        }
+        match self.cursor.next(self.tree) {
+            Some(Ok((key, v_loc))) => self.read_entry(key, v_loc),
+            Some(Err(e)) => Some(Err(e)),
+            None => None
+        }
+    }
+}
+
+impl<'a, K, V, S: Storage> DoubleEndedIterator for Range<'a, K, V, S>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if !self.cursor.back_started {
+            let hi = match self.end {
+                Bound::Included(ref k) => Bound::Included(k),
+                Bound::Excluded(ref k) => Bound::Excluded(k),
+                Bound::Unbounded => Bound::Unbounded
+            };
+            if let Err(e) = self.cursor.seek_back(self.tree, hi) { return self.cursor.fail(e) }
+        }
+        match self.cursor.next_back(self.tree) {
+            Some(Ok((key, v_loc))) => self.read_entry(key, v_loc),
+            Some(Err(e)) => Some(Err(e)),
+            None => None
+        }
+    }
+}
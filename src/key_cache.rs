@@ -0,0 +1,120 @@
+use std::collections::{ HashMap, VecDeque };
+use std::mem;
+
+/// The number of bytes of deserialized keys a PBTree keeps by default.
+pub const DEFAULT_KEY_CACHE_SIZE: usize = 1 << 20;
+
+/// A rough count of the bytes the cache spends on each key besides the key itself: its entries
+/// in the map and the clock.
+const ENTRY_OVERHEAD: usize = 48;
+
+struct Entry<K> {
+    key: K,
+    /// The bytes the key is counted as taking up.
+    size: usize,
+    /// Whether the key has been used since the clock hand last passed it.
+    referenced: bool
+}
+
+/// Keys that have been deserialized from the key file, by their location in it, so the keys
+/// compared most often (those near the root) are not deserialized over and over. Once the keys
+/// take up more than a set number of bytes, keys are dropped in CLOCK order: a hit only marks
+/// the key as used, which keeps hits about as cheap as a hash lookup.
+///
+/// The memory a key takes up is estimated from the size of K and of the serialized key, which is
+/// about right for keys such as Strings and Vecs that own a single buffer.
+pub struct KeyCache<K> {
+    /// The most bytes the cached keys may take up.
+    limit: usize,
+    used: usize,
+    keys: HashMap<u64, Entry<K>>,
+    /// The locations of the cached keys, in the order the hand visits them. Locations of keys
+    /// that were removed are skipped over and dropped when the hand reaches them.
+    clock: VecDeque<u64>
+}
+
+impl<K> KeyCache<K> {
+    pub fn new(limit: usize) -> Self {
+        KeyCache { limit, used: 0, keys: HashMap::new(), clock: VecDeque::new() }
+    }
+
+    /// The key at pos, if it is cached.
+    #[inline(always)]
+    pub fn get(&mut self, pos: u64) -> Option<&K> {
+        match self.keys.get_mut(&pos) {
+            Some(entry) => {
+                entry.referenced = true;
+                Some(&entry.key)
+            },
+            None => None
+        }
+    }
+
+    /// Caches the key at pos, which took up serialized_size bytes in the key file, dropping
+    /// other keys to make room if need be. Keys too large to ever fit are not cached.
+    pub fn insert(&mut self, pos: u64, key: K, serialized_size: usize) {
+        let size = mem::size_of::<K>() + serialized_size + ENTRY_OVERHEAD;
+        if size > self.limit { return }
+        self.remove(pos);
+        let room = self.limit - size;
+        self.shrink(room);
+        self.keys.insert(pos, Entry { key, size, referenced: false });
+        self.clock.push_back(pos);
+        self.used += size;
+    }
+
+    /// Drops the key at pos, e.g. because it was freed and its space may be reused.
+    pub fn remove(&mut self, pos: u64) {
+        if let Some(entry) = self.keys.remove(&pos) {
+            self.used -= entry.size;
+        }
+        // The clock forgets pos once the hand reaches it, or sooner if it is only garbage
+        if self.clock.len() > 2 * self.keys.len() + 16 {
+            let keys = &self.keys;
+            self.clock.retain(|pos| keys.contains_key(pos));
+        }
+    }
+
+    /// Drops every key.
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.clock.clear();
+        self.used = 0;
+    }
+
+    /// The most bytes the cached keys may take up.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Changes the most bytes the cached keys may take up, dropping keys until they fit.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.shrink(limit);
+    }
+
+    /// Drops keys until the rest take up at most bytes. Keys used since the hand last passed
+    /// them get another round.
+    fn shrink(&mut self, bytes: usize) {
+        while self.used > bytes {
+            let pos = match self.clock.pop_front() {
+                Some(pos) => pos,
+                None => break
+            };
+            let evict = match self.keys.get_mut(&pos) {
+                Some(ref mut entry) if entry.referenced => {
+                    entry.referenced = false;
+                    false
+                },
+                Some(_) => true,
+                // Removed already
+                None => continue
+            };
+            if evict {
+                if let Some(entry) = self.keys.remove(&pos) { self.used -= entry.size; }
+            } else {
+                self.clock.push_back(pos);
+            }
+        }
+    }
+}
@@ -1,39 +1,100 @@
-#![feature(rand)]
-#![feature(box_syntax)]
 extern crate raw_serde;
-extern crate rand;
+extern crate memmap;
 
-mod test_tree;
 mod file_buffer;
 mod btree;
 mod node;
 mod priority_queue;
+mod iter;
+mod wal;
+mod slab_policy;
+mod header;
+mod free_list;
+mod bulk;
+mod compact;
+mod inline_key;
+mod key_cache;
+mod storage;
+mod mmap_file;
+mod single_file;
+mod shared;
+#[macro_use]
+mod type_tag;
 pub use btree::*;
-pub use test_tree::*;
+pub use bulk::DEFAULT_FILL;
+pub use inline_key::*;
+pub use type_tag::TypeTag;
+pub use key_cache::DEFAULT_KEY_CACHE_SIZE;
+pub use file_buffer::{ BufFile, FileReader };
+pub use storage::{ Storage, SharedStorage, SharedRead, FileStorage };
+pub use mmap_file::{ MmapFile, MmapReader, MMAP_CHUNK };
+pub use shared::{ SharedPBTree, DEFAULT_SHARED_NODE_CACHE_SIZE };
+pub use single_file::{ SingleFile, CONTAINER_MAGIC, CONTAINER_VERSION, CONTAINER_HEADER_SIZE, PAGE_SIZE };
+pub use slab_policy::*;
+pub use iter::*;
+
+/// A path in the temp directory for the files of a test, unique to the test and the process.
+/// The files of a tree at the path, and of its compactions and bulk loads, are removed when it
+/// is dropped, whether or not the test passed.
+#[cfg(test)]
+struct TestPath(String);
+
+#[cfg(test)]
+impl TestPath {
+    fn new(name: &str) -> TestPath {
+        let path = std::env::temp_dir().join(format!("btree_{}_{}", name, std::process::id()));
+        TestPath(path.to_string_lossy().into_owned())
+    }
+
+    /// The path with ext appended, e.g. the name of one of the files of a tree.
+    fn with(&self, ext: &str) -> String {
+        self.0.clone() + ext
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestPath {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestPath {
+    fn drop(&mut self) {
+        for base in ["", ".compact"].iter() {
+            for ext in ["", ".tree", ".key", ".val", ".pbt", ".wal", ".bulk", ".entries"].iter() {
+                let _ = std::fs::remove_file(self.with(base) + ext);
+            }
+        }
+    }
+}
 
 #[test]
+#[ignore]
 fn test_file_buffer_speed() {
+    // A benchmark rather than a test, so it only runs when asked for, with
+    // cargo test test_file_buffer_speed -- --ignored --nocapture
     use std::time::{ SystemTime };
-    use std::io::{ Error, Seek, SeekFrom, Write, Read, BufWriter};
-    use std::collections::HashMap;
-    #[allow(unused_imports)]
-    use raw_serde::*;
-    use std::cmp;
-    use std::fs::{ File, OpenOptions };
+    use std::io::{ Write, BufWriter };
+    use std::fs::{ self, OpenOptions };
     use file_buffer::*;
 
     let num_mb = 1024;
 
     let dat = vec![1u8; 1024];
 
-
-    let mut t2 = OpenOptions::new().read(true).write(true).truncate(true).create(true).open("xasd.tree").unwrap();
+    let dir = std::env::temp_dir();
+    let path2 = dir.join(format!("btree_test_file_buffer_speed_{}.bufwriter", std::process::id()));
+    let t2 = OpenOptions::new().read(true).write(true).truncate(true).create(true).open(&path2).unwrap();
     let mut file2 = BufWriter::new(t2);
 
     let now = SystemTime::now();
 
-    for i in 0..num_mb * 1024 {
-        file2.write(&dat[0..]).unwrap();
+    for _ in 0..num_mb * 1024 {
+        file2.write_all(&dat[0..]).unwrap();
     }
 
     match now.elapsed() {
@@ -44,13 +105,14 @@ fn test_file_buffer_speed() {
         Err(_) => panic!("Error measuring time.."),
     };
 
-    let mut t1 = OpenOptions::new().read(true).write(true).truncate(true).create(true).open("test.tree").unwrap();
+    let path1 = dir.join(format!("btree_test_file_buffer_speed_{}.buffile", std::process::id()));
+    let t1 = OpenOptions::new().read(true).write(true).truncate(true).create(true).open(&path1).unwrap();
     let mut file = BufFile::with_capacity(2, t1).unwrap();
 
     let now = SystemTime::now();
 
-    for i in 0..num_mb * 1024 {
-        file.write(&dat[0..]).unwrap();
+    for _ in 0..num_mb * 1024 {
+        file.write_all(&dat[0..]).unwrap();
     }
 
     match now.elapsed() {
@@ -61,12 +123,18 @@ fn test_file_buffer_speed() {
         Err(_) => panic!("Error measuring time.."),
     };
 
+    drop(file2);
+    drop(file);
+    fs::remove_file(&path2).unwrap();
+    fs::remove_file(&path1).unwrap();
 }
 
 #[test]
+#[ignore]
 fn it_works() {
-    return;
-    let mut t = PBTree::<String, String>::new("heckaroo.dat").unwrap();
+    // A benchmark rather than a test, so it only runs when asked for, with
+    // cargo test it_works -- --ignored --nocapture
+    let mut t = PBTree::<String, String, Vec<u8>>::in_memory().unwrap();
     use std::time::{ SystemTime };
 
     let x = 16*16*16*16;
@@ -83,59 +151,1337 @@ fn it_works() {
 
     let now = SystemTime::now();
     for i in 0..x {
-        let x = t.search(&i.to_string()).unwrap().unwrap();
-        //let y = test.search(&i.to_string()).unwrap().unwrap();
+        t.search(&i.to_string()).unwrap().unwrap();
     }
     match now.elapsed() {
         Ok(a) => println!("time to search for {} string string pairs: {:?}", x, a),
         Err(_) => panic!("Error measuring time.."),
     };
 
-    let mut t = TestTree::<String, String>::new("heckaroo.dat").unwrap();
+}
 
+#[test]
+fn test_file_buffer() {
+    use std::fs::{ self, OpenOptions };
+    use std::io::{ Seek, SeekFrom, Read, Write };
+    use file_buffer::*;
 
-    let now = SystemTime::now();
-    for i in 0..x {
-        let p = i.to_string();
-        t.insert(&p, &p).unwrap();
+    let path = std::env::temp_dir().join(format!("btree_test_file_buffer_{}", std::process::id()));
+    let test_file = OpenOptions::new().read(true).write(true).truncate(true).create(true).open(&path).unwrap();
+    // Two slabs, so most writes below evict a dirty slab
+    let mut file = BufFile::with_capacity(2, test_file).unwrap();
+    let mut model = vec![0u8; 4 * DEFAULT_SLAB_SIZE];
+
+    let mut seed = 6712u64;
+    for _ in 0..2000 {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        let pos = (seed >> 33) as usize % (model.len() - 4096);
+        let len = (seed >> 17) as usize % 4096;
+        let byte = seed as u8;
+        for b in model[pos..pos + len].iter_mut() {
+            *b = byte;
+        }
+        file.seek(SeekFrom::Start(pos as u64)).unwrap();
+        file.write_all(&model[pos..pos + len]).unwrap();
     }
-    match now.elapsed() {
-        Ok(a) => println!("time to insert {} string string pairs: {:?}", x, a),
-        Err(_) => panic!("Error measuring time.."),
+
+    let end = file.seek(SeekFrom::End(0)).unwrap() as usize;
+    let mut read = vec![0u8; end];
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_exact(&mut read).unwrap();
+    assert_eq!(&read[..], &model[..end]);
+
+    drop(file);
+    assert_eq!(&fs::read(&path).unwrap()[..], &model[..end]);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_reopen() {
+    let path = TestPath::new("test_reopen");
+    let missing = TestPath::new("test_reopen_missing");
+
+    {
+        let mut t = PBTree::<u64, String>::new(&*path).unwrap();
+        for i in 0..2000u64 {
+            t.insert(&i, &i.to_string()).unwrap();
+        }
+    }
+    {
+        let mut t = PBTree::<u64, String>::open(&*path).unwrap();
+        for i in 0..2000u64 {
+            assert_eq!(t.search(&i).unwrap(), Some(i.to_string()));
+        }
+        for i in 2000..4000u64 {
+            t.insert(&i, &i.to_string()).unwrap();
+        }
+    }
+    let mut t = PBTree::<u64, String>::open_or_create(&*path).unwrap();
+    for i in 0..4000u64 {
+        assert_eq!(t.search(&i).unwrap(), Some(i.to_string()));
+    }
+
+    assert!(PBTree::<u64, String>::open(&*missing).is_err());
+}
+
+#[test]
+fn test_remove() {
+    let mut t = PBTree::<u64, u64, Vec<u8>>::in_memory().unwrap();
+    let n = 5000u64;
+    for i in 0..n {
+        t.insert(&i, &(i * 3)).unwrap();
+    }
+
+    // Remove the keys in a scrambled order so every rebalancing case gets exercised
+    let mut order: Vec<u64> = (0..n).collect();
+    let mut seed = 12345u64;
+    for i in (1..order.len()).rev() {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        order.swap(i, (seed >> 33) as usize % (i + 1));
+    }
+
+    for (removed, k) in order.iter().enumerate() {
+        assert_eq!(t.remove(k).unwrap(), Some(k * 3));
+        assert_eq!(t.remove(k).unwrap(), None);
+        if removed % 500 == 0 {
+            for j in order[removed + 1..].iter() {
+                assert_eq!(t.search(j).unwrap(), Some(j * 3));
+            }
+        }
+    }
+    for i in 0..n {
+        assert!(!t.contains_key(&i).unwrap());
+    }
+
+    // The emptied tree is still usable
+    for i in 0..100u64 {
+        t.insert(&i, &i).unwrap();
+    }
+    for i in 0..100u64 {
+        assert_eq!(t.search(&i).unwrap(), Some(i));
+    }
+}
+
+#[test]
+fn test_upsert() {
+    let mut t = PBTree::<u64, String, Vec<u8>>::in_memory().unwrap();
+    for i in 0..3000u64 {
+        assert_eq!(t.insert(&i, &i.to_string()).unwrap(), None);
+    }
+    for i in 0..3000u64 {
+        assert_eq!(t.insert(&i, &(i * 2).to_string()).unwrap(), Some(i.to_string()));
+    }
+    for i in 0..3000u64 {
+        assert_eq!(t.search(&i).unwrap(), Some((i * 2).to_string()));
+        assert_eq!(t.remove(&i).unwrap(), Some((i * 2).to_string()));
+        assert_eq!(t.search(&i).unwrap(), None);
+    }
+
+    t.insert_unique(&7, &"seven".to_string()).unwrap();
+    assert_eq!(t.insert_unique(&7, &"eight".to_string()).unwrap_err().kind(), ::std::io::ErrorKind::AlreadyExists);
+    assert_eq!(t.search(&7).unwrap(), Some("seven".to_string()));
+}
+
+#[test]
+fn test_iter() {
+    let mut t = PBTree::<u64, u64, Vec<u8>>::in_memory().unwrap();
+    assert_eq!(t.iter().count(), 0);
+
+    let n = 4000u64;
+    // Insert in a scrambled order; 7919 is prime, so this visits every key once
+    for i in 0..n {
+        let k = (i * 7919) % n;
+        t.insert(&k, &(k + 1)).unwrap();
+    }
+
+    let entries: Vec<(u64, u64)> = t.iter().map(|e| e.unwrap()).collect();
+    assert_eq!(entries, (0..n).map(|k| (k, k + 1)).collect::<Vec<_>>());
+
+    let keys: Vec<u64> = t.keys().map(|k| k.unwrap()).collect();
+    assert_eq!(keys, (0..n).collect::<Vec<_>>());
+
+    let values: Vec<u64> = t.values().map(|v| v.unwrap()).collect();
+    assert_eq!(values, (1..n + 1).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_range() {
+    let mut t = PBTree::<u64, u64, Vec<u8>>::in_memory().unwrap();
+    // Only even keys, so bounds fall both on and between keys
+    for i in 0..2000u64 {
+        t.insert(&(i * 2), &i).unwrap();
+    }
+
+    let keys = |r: Vec<Result<(u64, u64), ::std::io::Error>>| r.into_iter().map(|e| e.unwrap().0).collect::<Vec<u64>>();
+
+    assert_eq!(keys(t.range(100..110).collect()), vec![100, 102, 104, 106, 108]);
+    assert_eq!(keys(t.range(101..=110).collect()), vec![102, 104, 106, 108, 110]);
+    assert_eq!(keys(t.range((::std::ops::Bound::Excluded(100), ::std::ops::Bound::Included(106))).collect()),
+               vec![102, 104, 106]);
+    assert_eq!(keys(t.range(3990..).collect()), vec![3990, 3992, 3994, 3996, 3998]);
+    assert_eq!(keys(t.range(..6).collect()), vec![0, 2, 4]);
+    assert_eq!(t.range(..).count(), 2000);
+    assert_eq!(t.range(50..50).count(), 0);
+    assert_eq!(t.range(5000..).count(), 0);
+
+    // Crosses many node boundaries in both directions
+    assert_eq!(keys(t.range(500..1500).rev().collect()), (250..750).rev().map(|i| i * 2).collect::<Vec<u64>>());
+    assert_eq!(keys(t.range(1001..).take(100).collect()), (501..601).map(|i| i * 2).collect::<Vec<u64>>());
+
+    // The two ends stop when they meet
+    let mut r = t.range(10..=20);
+    let mut seen = vec![];
+    while let Some(e) = r.next() {
+        seen.push(e.unwrap().0);
+        match r.next_back() { Some(e) => seen.push(e.unwrap().0), None => break }
+    }
+    seen.sort();
+    assert_eq!(seen, vec![10, 12, 14, 16, 18, 20]);
+}
+
+#[test]
+fn test_wal_replay() {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::mem;
+
+    let path = TestPath::new("test_wal_replay");
+
+    {
+        let mut t = PBTree::<u64, String>::new(&*path).unwrap();
+        for i in 0..3000u64 {
+            t.insert(&i, &i.to_string()).unwrap();
+        }
+        for i in 0..1000u64 {
+            t.remove(&i).unwrap();
+        }
+        // Simulate a crash: nothing buffered is written back, only the log survives
+        mem::forget(t);
+    }
+
+    // A transaction torn part way through being appended is ignored
+    let mut wal = OpenOptions::new().append(true).open(path.with(".wal")).unwrap();
+    wal.write_all(&[200, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3]).unwrap();
+
+    let mut t = PBTree::<u64, String>::open(&*path).unwrap();
+    for i in 0..1000u64 {
+        assert_eq!(t.search(&i).unwrap(), None);
+    }
+    for i in 1000..3000u64 {
+        assert_eq!(t.search(&i).unwrap(), Some(i.to_string()));
+    }
+}
+
+#[test]
+fn test_sync() {
+    use std::fs;
+    use std::mem;
+
+    let path = TestPath::new("test_sync");
+
+    {
+        let mut t = PBTree::<String, u64>::new(&*path).unwrap();
+        for i in 0..2000u64 {
+            t.insert(&i.to_string(), &i).unwrap();
+        }
+        t.sync().unwrap();
+        mem::forget(t);
+    }
+    // Everything synced is in the three files themselves, without help from the log
+    fs::remove_file(path.with(".wal")).unwrap();
+
+    let mut t = PBTree::<String, u64>::open(&*path).unwrap();
+    for i in 0..2000u64 {
+        assert_eq!(t.search(&i.to_string()).unwrap(), Some(i));
+    }
+}
+
+#[test]
+fn test_file_buffer_dirty() {
+    use std::fs::OpenOptions;
+    use std::io::{ Read, Seek, SeekFrom, Write };
+    use file_buffer::*;
+
+    let path = TestPath::new("test_file_buffer_dirty");
+    let evict_path = TestPath::new("test_file_buffer_dirty_evict");
+
+    let file = OpenOptions::new().read(true).write(true).truncate(true).create(true).open(&*path).unwrap();
+    let mut file = BufFile::new(file).unwrap();
+    file.write_all(&[1u8; 300]).unwrap();
+    file.flush().unwrap();
+
+    // Change the file behind the BufFile's back; its slab still holds the old bytes
+    let mut raw = OpenOptions::new().read(true).write(true).open(&*path).unwrap();
+    raw.seek(SeekFrom::Start(100)).unwrap();
+    raw.write_all(&[7u8; 10]).unwrap();
+
+    // Reading doesn't dirty the slab, and writing elsewhere only writes back what changed
+    let mut buf = [0u8; 50];
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_exact(&mut buf).unwrap();
+    file.flush().unwrap();
+    file.seek(SeekFrom::Start(200)).unwrap();
+    file.write_all(&[2u8; 10]).unwrap();
+    file.flush().unwrap();
+
+    let mut contents = vec![];
+    raw.seek(SeekFrom::Start(0)).unwrap();
+    raw.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents.len(), 300);
+    assert_eq!(&contents[100..110], &[7u8; 10]);
+    assert_eq!(&contents[200..210], &[2u8; 10]);
+    assert_eq!(&contents[0..100], &[1u8; 100][..]);
+
+    // A write that spans slabs and goes past the end keeps its first part when the slab holding
+    // it is evicted to make room for the next
+    let file = OpenOptions::new().read(true).write(true).truncate(true).create(true).open(&*evict_path).unwrap();
+    let mut file = BufFile::with_slab_size(4096, Box::new(LfuPolicy::new(1)), file).unwrap();
+    for i in 0..20u8 {
+        file.write_all(&[i; 3000]).unwrap();
+    }
+    let mut buf = [0u8; 3000];
+    file.seek(SeekFrom::Start(0)).unwrap();
+    for i in 0..20u8 {
+        file.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == i));
+    }
+}
+
+#[test]
+fn test_slab_policies() {
+    use std::fs::OpenOptions;
+    use std::io::{ Read, Seek, SeekFrom, Write };
+
+    let tree_path = TestPath::new("test_slab_policies_tree");
+
+    let policies: Vec<Box<dyn SlabPolicy>> = vec![
+        Box::new(LfuPolicy::new(3)),
+        Box::new(LruPolicy::new(3)),
+        Box::new(ClockPolicy::new(3)),
+        Box::new(ArcPolicy::new(3))
+    ];
+    for (n, policy) in policies.into_iter().enumerate() {
+        let name = TestPath::new(&format!("test_slab_policies_{}", n));
+        let file = OpenOptions::new().read(true).write(true).truncate(true).create(true).open(&*name).unwrap();
+        let mut file = BufFile::with_policy(policy, file).unwrap();
+
+        // Scatter writes over 8 MiB so slabs are evicted constantly, mirroring them in memory
+        let len = 8 * 1024 * 1024;
+        let mut shadow = vec![0u8; len];
+        file.write_all(&shadow).unwrap();
+        let mut seed = 99u64;
+        for i in 0..4000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let pos = (seed >> 33) as usize % (len - 5000);
+            let data = vec![(i % 251) as u8 + 1; 1 + i % 4999];
+            file.seek(SeekFrom::Start(pos as u64)).unwrap();
+            file.write_all(&data).unwrap();
+            shadow[pos..pos + data.len()].clone_from_slice(&data);
+
+            // Read back from somewhere else, likely in an evicted slab
+            let other = ((seed >> 13) as usize) % (len - 100);
+            let mut buf = [0u8; 100];
+            file.seek(SeekFrom::Start(other as u64)).unwrap();
+            file.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &shadow[other..other + 100]);
+        }
+        file.flush().unwrap();
+
+        let mut contents = vec![];
+        OpenOptions::new().read(true).open(&*name).unwrap().read_to_end(&mut contents).unwrap();
+        assert!(contents == shadow);
+    }
+
+    // A slab that was used heavily long ago is evicted once another has been used since
+    let mut lfu = LfuPolicy::new(2);
+    lfu.loaded(0, 0);
+    for _ in 0..1000 { lfu.accessed(0); }
+    lfu.loaded(1, 1 << 20);
+    for _ in 0..200 { lfu.accessed(1); }
+    assert_eq!(lfu.evict(2 << 20, &|_| false), Some(0));
+
+    // Trees can use any of the policies
+    for &policy in [PolicyKind::Lfu, PolicyKind::Lru, PolicyKind::Clock, PolicyKind::Arc].iter() {
+        let options = Options { slab_size: 4096, slabs: 4, policy, ..Options::default() };
+        let mut tree = PBTree::<u64, u64>::with_options(&*tree_path, options).unwrap();
+        for i in 0..3000u64 {
+            tree.insert(&(i * 7919 % 3000), &i).unwrap();
+        }
+        for i in 0..3000u64 {
+            assert_eq!(tree.search(&(i * 7919 % 3000)).unwrap(), Some(i));
+        }
+    }
+}
+
+#[test]
+fn test_slab_size() {
+    use std::fs::OpenOptions;
+    use std::io::{ Read, Seek, SeekFrom, Write };
+
+    let dat = TestPath::new("test_slab_size_dat");
+    let path = TestPath::new("test_slab_size");
+    let bulk = TestPath::new("test_slab_size_bulk");
+
+    let file = OpenOptions::new().read(true).write(true).truncate(true).create(true).open(&*dat).unwrap();
+    assert!(BufFile::with_slab_size(3000, Box::new(LruPolicy::new(4)), file.try_clone().unwrap()).is_err());
+    let mut file = BufFile::with_slab_size(4096, Box::new(LruPolicy::new(4)), file).unwrap();
+
+    // Unaligned buffers longer than a slab span three slabs
+    let data: Vec<u8> = (0..10000).map(|i| (i % 253) as u8).collect();
+    file.seek(SeekFrom::Start(4000)).unwrap();
+    file.write_all(&data).unwrap();
+    let mut buf = vec![0u8; 10000];
+    file.seek(SeekFrom::Start(4000)).unwrap();
+    file.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+
+    file.set_slab_size(1 << 16).unwrap();
+    let mut buf = vec![0u8; 10000];
+    file.seek(SeekFrom::Start(4000)).unwrap();
+    file.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+
+    // Trees take their slab size from their options, which their files are reopened with
+    let pages = Options { slab_size: 4096, slabs: 256, ..Options::default() };
+    let bad = Options { slab_size: 3000, ..Options::default() };
+    assert_eq!(PBTree::<u64, Vec<u8>>::with_options(&*path, bad).err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+    let mut tree = PBTree::<u64, Vec<u8>>::with_options(&*path, pages).unwrap();
+    assert_eq!(tree.treefile.slab_size(), 4096);
+    for i in 0..2000u64 {
+        tree.insert(&i, &vec![i as u8; 5000]).unwrap();
+    }
+    tree.compact().unwrap();
+    assert_eq!(tree.valfile.slab_size(), 4096);
+    for i in 0..2000u64 {
+        assert_eq!(tree.search(&i).unwrap(), Some(vec![i as u8; 5000]));
+    }
+    drop(tree);
+    let tree = PBTree::<u64, Vec<u8>>::open_with_options(&*path, pages).unwrap();
+    assert_eq!(tree.keyfile.slab_size(), 4096);
+    assert_eq!(tree.len(), 2000);
+
+    let large = Options { slab_size: 1 << 22, slabs: 4, ..Options::default() };
+    let tree = PBTree::<u64, u64>::bulk_load_with_options(&*bulk, large, (0..10000u64).map(|i| (i, i))).unwrap();
+    assert_eq!(tree.treefile.slab_size(), 1 << 22);
+    assert_eq!(tree.len(), 10000);
+}
+
+#[test]
+fn test_degree() {
+    use std::fs::OpenOptions;
+    use std::io::{ Seek, SeekFrom, Write };
+
+    let bad = TestPath::new("test_degree_bad");
+    assert!(PBTree::<u64, u64>::with_degree(&*bad, 1).is_err());
+
+    let paths: Vec<(u64, TestPath)> = [2u64, 3, 200].iter().map(|&t| (t, TestPath::new(&format!("test_degree_{}", t)))).collect();
+    for &(t, ref path) in paths.iter() {
+        {
+            let mut tree = PBTree::<u64, u64>::with_degree(&**path, t).unwrap();
+            for i in 0..3000u64 {
+                tree.insert(&(i * 7919 % 3000), &i).unwrap();
+            }
+            for i in 0..1500u64 {
+                assert!(tree.remove(&(i * 2)).unwrap().is_some());
+            }
+        }
+        let mut tree = PBTree::<u64, u64>::open(&**path).unwrap();
+        assert_eq!(tree.degree(), t);
+        let keys: Vec<u64> = tree.keys().map(|k| k.unwrap()).collect();
+        assert_eq!(keys, (0..1500u64).map(|i| i * 2 + 1).collect::<Vec<u64>>());
+    }
+
+    // A degree of 0 recorded in the header is rejected
+    {
+        let mut file = OpenOptions::new().write(true).open(paths[0].1.with(".tree")).unwrap();
+        file.seek(SeekFrom::Start(24)).unwrap();
+        file.write_all(&[0u8; 8]).unwrap();
+    }
+    assert!(PBTree::<u64, u64>::open(&*paths[0].1).is_err());
+}
+
+#[test]
+fn test_header() {
+    use std::fs::OpenOptions;
+    use std::io::{ Seek, SeekFrom, Write };
+
+    let path = TestPath::new("test_header");
+
+    {
+        let mut tree = PBTree::<u64, String>::new(&*path).unwrap();
+        for i in 0..100u64 {
+            tree.insert(&i, &i.to_string()).unwrap();
+        }
+    }
+    assert!(PBTree::<u64, String>::open(&*path).is_ok());
+
+    // The wrong key or value type is caught
+    let err = PBTree::<u32, String>::open(&*path).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(PBTree::<u64, u64>::open(&*path).is_err());
+
+    // Types are told apart by their tags, which don't depend on the compiler
+    assert_eq!(u64::type_tag(), "u64");
+    assert_eq!(<[u8; 32]>::type_tag(), "[u8; 32]");
+    assert_eq!(<Vec<(u32, String)>>::type_tag(), "Vec<(u32, String)>");
+    assert_eq!(header::fingerprint::<u64>(), wal::checksum(b"u64"));
+    assert_eq!(usize::type_tag(), format!("u{}", std::mem::size_of::<usize>() * 8));
+    assert_eq!(isize::type_tag(), format!("i{}", std::mem::size_of::<isize>() * 8));
+
+    // Types of our own are tagged by the macro, by their type name, or opt out with an empty tag
+    struct Point;
+    struct Named;
+    struct Untagged;
+    type_tag!(Point);
+    impl TypeTag for Named {}
+    impl TypeTag for Untagged {
+        fn type_tag() -> String { String::new() }
+    }
+    assert_eq!(Point::type_tag(), "Point");
+    assert!(Named::type_tag().ends_with("::Named"));
+    assert_eq!(header::fingerprint::<Untagged>(), 0);
+    let bytes = header::Header::new::<u64, Untagged>(header::HEADER_SIZE, MIN_DEGREE, 0, 0, free_list::FreeLists::new()).to_bytes();
+    assert!(header::Header::read::<u64, String>(&mut &bytes[..], "untagged").is_ok());
+    assert!(header::Header::read::<u64, Untagged>(&mut &bytes[..], "untagged").is_ok());
+    assert!(header::Header::read::<u32, Untagged>(&mut &bytes[..], "untagged").is_err());
+    let bytes = header::Header::new::<u64, String>(header::HEADER_SIZE, MIN_DEGREE, 0, 0, free_list::FreeLists::new()).to_bytes();
+    assert!(header::Header::read::<u64, Untagged>(&mut &bytes[..], "tagged").is_ok());
+
+    // So is an unknown format version
+    {
+        let mut file = OpenOptions::new().write(true).open(path.with(".tree")).unwrap();
+        file.seek(SeekFrom::Start(8)).unwrap();
+        file.write_all(&[99, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    }
+    let err = PBTree::<u64, String>::open(&*path).err().unwrap();
+    assert!(err.to_string().contains("version 99"));
+
+    // And a file that isn't a tree at all
+    {
+        let mut file = OpenOptions::new().write(true).open(path.with(".tree")).unwrap();
+        file.write_all(b"garbage!").unwrap();
+    }
+    let err = PBTree::<u64, String>::open(&*path).err().unwrap();
+    assert!(err.to_string().contains("not a tree file"));
+}
+
+#[test]
+fn test_len() {
+    let path = TestPath::new("test_len");
+
+    {
+        let mut tree = PBTree::<u64, u64>::with_degree(&*path, 2).unwrap();
+        assert!(tree.is_empty());
+        let stats = tree.stats().unwrap();
+        assert_eq!((stats.len, stats.height, stats.nodes, stats.leaves), (0, 1, 1, 1));
+
+        for i in 0..1000u64 {
+            tree.insert(&i, &i).unwrap();
+        }
+        // Replacing values and failed inserts leave the count alone
+        for i in 0..100u64 {
+            tree.insert(&i, &(i + 1)).unwrap();
+        }
+        assert!(tree.insert_unique(&5, &5).is_err());
+        for i in 0..300u64 {
+            tree.remove(&(i * 3)).unwrap();
+        }
+        assert!(tree.remove(&3).unwrap().is_none());
+        assert_eq!(tree.len(), 700);
+    }
+    let mut tree = PBTree::<u64, u64>::open(&*path).unwrap();
+    assert_eq!(tree.len(), 700);
+    assert!(!tree.is_empty());
+
+    let stats = tree.stats().unwrap();
+    assert_eq!(stats.len, 700);
+    assert!(stats.height > 2 && stats.leaves < stats.nodes);
+    // A tree of degree 2 holds between 1 and 3 keys per node
+    assert!(stats.nodes <= 700 && stats.nodes * 3 >= 700);
+}
+
+#[test]
+fn test_free_space() {
+    let path = TestPath::new("test_free_space");
+
+    let sizes = {
+        let mut tree = PBTree::<u64, String>::new(&*path).unwrap();
+        for i in 0..3000u64 {
+            tree.insert(&i, &format!("value {}", i)).unwrap();
+        }
+        let stats = tree.stats().unwrap();
+        (stats.tree_bytes, stats.key_bytes, stats.val_bytes)
     };
+    {
+        // Removing everything and inserting it again reuses the freed space, even after reopening
+        let mut tree = PBTree::<u64, String>::open(&*path).unwrap();
+        for i in 0..3000u64 {
+            assert_eq!(tree.remove(&i).unwrap(), Some(format!("value {}", i)));
+        }
+    }
+    let mut tree = PBTree::<u64, String>::open(&*path).unwrap();
+    for i in 0..3000u64 {
+        tree.insert(&i, &format!("value {}", i)).unwrap();
+    }
+    // Values of the same size replace each other in place
+    for _ in 0..5 {
+        for i in 0..3000u64 {
+            tree.insert(&i, &format!("VALUE {}", i)).unwrap();
+        }
+    }
+    let stats = tree.stats().unwrap();
+    assert!(stats.tree_bytes <= sizes.0 + sizes.0 / 10);
+    assert_eq!((stats.key_bytes, stats.val_bytes), (sizes.1, sizes.2));
+    for i in 0..3000u64 {
+        assert_eq!(tree.search(&i).unwrap(), Some(format!("VALUE {}", i)));
+    }
+
+    // Values whose sizes keep changing are cut out of freed blocks of other sizes. What is left
+    // of those blocks is reused, and neighbouring free blocks are merged, so the value file
+    // levels off rather than growing with every round
+    let mut tree = PBTree::<u64, String, Vec<u8>>::in_memory().unwrap();
+    let value = |i: u64, round: u64| "v".repeat(((i * 7 + round * 13) % 61) as usize);
+    let mut first = 0;
+    for round in 0..40 {
+        for i in 0..2000u64 {
+            tree.insert(&i, &value(i, round)).unwrap();
+        }
+        if round == 0 { first = tree.stats().unwrap().val_bytes; }
+    }
+    assert!(tree.stats().unwrap().val_bytes < first * 3 / 2);
+    for i in 0..2000u64 {
+        assert_eq!(tree.search(&i).unwrap(), Some(value(i, 39)));
+    }
+}
+
+#[test]
+fn test_compact() {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    let path = TestPath::new("test_compact");
 
+    let mut model = BTreeMap::new();
+    {
+        let mut tree = PBTree::<u64, String>::with_degree(&*path, 4).unwrap();
+        for i in 0..4000u64 {
+            tree.insert(&i, &"x".repeat((i % 50) as usize)).unwrap();
+        }
+        for i in 0..3000u64 {
+            tree.remove(&i).unwrap();
+        }
+        for i in 3000..4000u64 {
+            tree.insert(&i, &i.to_string()).unwrap();
+            model.insert(i, i.to_string());
+        }
+
+        let before = tree.stats().unwrap();
+        let reclaimed = tree.compact().unwrap();
+        let after = tree.stats().unwrap();
+        assert!(reclaimed > 0);
+        assert_eq!(reclaimed, before.tree_bytes + before.key_bytes + before.val_bytes
+            - after.tree_bytes - after.key_bytes - after.val_bytes);
+        assert_eq!((after.len, after.height), (1000, 4));
+        assert!(after.nodes < before.nodes);
+        assert!(!Path::new(&path.with(".compact")).exists());
+        assert!(!Path::new(&path.with(".compact.tree")).exists());
+        assert_eq!(tree.iter().map(|e| e.unwrap()).collect::<BTreeMap<u64, String>>(), model);
+    }
+    {
+        // Compacting a little at a time, while the tree changes on both sides of the cursor
+        let mut tree = PBTree::<u64, String>::open(&*path).unwrap();
+        assert_eq!(tree.iter().map(|e| e.unwrap()).collect::<BTreeMap<u64, String>>(), model);
+        let mut steps = 0;
+        let mut i = 0u64;
+        while tree.compact_step(50).unwrap().is_none() {
+            steps += 1;
+            for k in &[3000 + i * 7 % 1000, 3000 + i * 13 % 1000, 5000 + i] {
+                tree.insert(k, &format!("step {}", i)).unwrap();
+                model.insert(*k, format!("step {}", i));
+            }
+            let k = 3000 + i * 31 % 1000;
+            assert_eq!(tree.remove(&k).unwrap(), model.remove(&k));
+            i += 1;
+        }
+        assert!(steps > 10);
+        assert_eq!(tree.len(), model.len() as u64);
+        assert_eq!(tree.iter().map(|e| e.unwrap()).collect::<BTreeMap<u64, String>>(), model);
+        tree.insert(&10000, &"after".to_string()).unwrap();
+        model.insert(10000, "after".to_string());
+    }
+    let mut tree = PBTree::<u64, String>::open(&*path).unwrap();
+    assert_eq!(tree.len(), model.len() as u64);
+    assert_eq!(tree.iter().map(|e| e.unwrap()).collect::<BTreeMap<u64, String>>(), model);
+
+    // A compaction that fails part way through the swap isn't abandoned. The tree can't be used
+    // until it is opened again, which finishes the swap.
+    for i in 0..1000u64 {
+        tree.remove(&(3000 + i)).unwrap();
+        model.remove(&(3000 + i));
+    }
+    let before = tree.stats().unwrap();
+    compact::FAIL_SWAP.with(|fail| fail.set(true));
+    assert!(tree.compact().is_err());
+    assert!(Path::new(&path.with(".compact")).exists());
+    assert!(tree.search(&10000).is_err());
+    assert!(tree.insert(&1, &"1".to_string()).is_err());
+    assert!(tree.compact().is_err());
+    drop(tree);
+    let mut tree = PBTree::<u64, String>::open(&*path).unwrap();
+    assert!(!Path::new(&path.with(".compact")).exists());
+    assert!(!Path::new(&path.with(".compact.val")).exists());
+    assert_eq!(tree.len(), model.len() as u64);
+    assert_eq!(tree.iter().map(|e| e.unwrap()).collect::<BTreeMap<u64, String>>(), model);
+    assert!(tree.stats().unwrap().val_bytes < before.val_bytes);
+}
+
+#[test]
+fn test_bulk_load() {
+    let path = TestPath::new("test_bulk_load");
+    let small = TestPath::new("test_bulk_load_small");
+
+    {
+        let tree = PBTree::<u64, String>::bulk_load(&*path, (0..20000u64).map(|i| (i * 2, i.to_string()))).unwrap();
+        assert_eq!(tree.len(), 20000);
+    }
+    let mut tree = PBTree::<u64, String>::open(&*path).unwrap();
+    assert_eq!(tree.len(), 20000);
+    assert_eq!(tree.search(&3000).unwrap(), Some("1500".to_string()));
+    assert_eq!(tree.search(&3001).unwrap(), None);
+    let keys = tree.keys().map(|k| k.unwrap()).collect::<Vec<u64>>();
+    assert_eq!(keys, (0..20000u64).map(|i| i * 2).collect::<Vec<u64>>());
+    // Nodes are filled to 90% of their 31 keys by default
+    let stats = tree.stats().unwrap();
+    assert!(stats.nodes <= 20000 / 27 + 30);
+
+    // The loaded tree can be modified like any other
+    for i in 0..20000u64 {
+        tree.insert(&(i * 2 + 1), &i.to_string()).unwrap();
+    }
+    for i in 0..10000u64 {
+        assert_eq!(tree.remove(&(i * 4)).unwrap(), Some((i * 2).to_string()));
+    }
+    assert_eq!(tree.len(), 30000);
+    assert_eq!(tree.range(..10).map(|e| e.unwrap().0).collect::<Vec<u64>>(), vec![1, 2, 3, 5, 6, 7, 9]);
+
+    // Full nodes, and the smallest possible trees
+    let mut full = PBTree::<u64, u64>::bulk_load_with(&*small, 3, 1.0, (0..1000u64).map(|i| (i, i))).unwrap();
+    let stats = full.stats().unwrap();
+    assert_eq!((stats.len, stats.leaves), (1000, 167));
+    assert_eq!(full.iter().map(|e| e.unwrap().1).sum::<u64>(), 999 * 500);
+    let mut empty = PBTree::<u64, u64>::bulk_load(&*small, vec![]).unwrap();
+    assert!(empty.is_empty());
+    assert_eq!(empty.stats().unwrap().nodes, 1);
+    let mut one = PBTree::<u64, u64>::bulk_load(&*small, vec![(7, 8)]).unwrap();
+    assert_eq!(one.search(&7).unwrap(), Some(8));
+
+    // Unsorted input and bad fills are refused
+    let err = PBTree::<u64, u64>::bulk_load(&*small, vec![(1, 1), (3, 3), (2, 2)]).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(!std::path::Path::new(&small.with(".bulk")).exists());
+    assert!(PBTree::<u64, u64>::bulk_load(&*small, vec![(1, 1), (1, 1)]).is_err());
+    assert!(PBTree::<u64, u64>::bulk_load_with(&*small, 3, 0.0, vec![]).is_err());
+    assert!(PBTree::<u64, u64>::bulk_load_with(&*small, 3, 1.5, vec![]).is_err());
+}
+
+#[test]
+fn test_insert_batch() {
+    use std::collections::BTreeMap;
+
+    let path = TestPath::new("test_insert_batch");
+
+    let mut model = BTreeMap::new();
+    {
+        let mut tree = PBTree::<u64, String>::with_degree(&*path, 3).unwrap();
+        assert_eq!(tree.insert_batch(vec![]).unwrap(), 0);
+        for round in 0..20u64 {
+            // Unsorted batches that overlap each other and contain duplicates
+            let batch = (0..1000u64).map(|i| ((i * 7919 + round * 131) % 5000, format!("{} {}", round, i))).collect::<Vec<_>>();
+            let mut added = 0;
+            for &(k, ref v) in batch.iter() {
+                if model.insert(k, v.clone()).is_none() { added += 1; }
+            }
+            assert_eq!(tree.insert_batch(batch).unwrap(), added);
+            assert_eq!(tree.len(), model.len() as u64);
+        }
+        assert_eq!(tree.insert_batch(vec![(1, "a".to_string()), (1, "b".to_string())]).unwrap(), 0);
+        model.insert(1, "b".to_string());
+        assert_eq!(tree.iter().map(|e| e.unwrap()).collect::<BTreeMap<u64, String>>(), model);
+    }
+    let mut tree = PBTree::<u64, String>::open(&*path).unwrap();
+    assert_eq!(tree.len(), model.len() as u64);
+    assert_eq!(tree.iter().map(|e| e.unwrap()).collect::<BTreeMap<u64, String>>(), model);
+    let stats = tree.stats().unwrap();
+    // Every node but the root holds between 2 and 5 keys
+    assert!(stats.nodes * 2 <= stats.len + 2 && stats.nodes * 5 >= stats.len);
+}
+
+#[test]
+fn test_inline_keys() {
+    use std::collections::BTreeMap;
+
+    let path = TestPath::new("test_inline_keys");
+    let arrays = TestPath::new("test_inline_keys_arrays");
+
+    let mut model = BTreeMap::new();
+    {
+        let mut tree = PBTree::<u64, String>::with_inline_keys(&*path, 4).unwrap();
+        for i in 0..5000u64 {
+            let k = i * 7919 % 5000;
+            tree.insert(&k, &k.to_string()).unwrap();
+            model.insert(k, k.to_string());
+        }
+        for i in 0..2000u64 {
+            assert_eq!(tree.remove(&(i * 2)).unwrap(), model.remove(&(i * 2)));
+        }
+        tree.insert_batch((5000..6000u64).map(|i| (i, i.to_string()))).unwrap();
+        model.extend((5000..6000u64).map(|i| (i, i.to_string())));
+        // The key file is never written to
+        assert_eq!(tree.stats().unwrap().key_bytes, 0);
+        assert_eq!(tree.range(10..20).map(|e| e.unwrap().0).collect::<Vec<u64>>(), vec![11, 13, 15, 17, 19]);
+    }
+    let mut tree = PBTree::<u64, String>::open(&*path).unwrap();
+    assert_eq!(tree.iter().map(|e| e.unwrap()).collect::<BTreeMap<u64, String>>(), model);
+    assert!(tree.compact().unwrap() > 0);
+    assert_eq!(tree.keys().map(|k| k.unwrap()).collect::<Vec<u64>>(), model.keys().cloned().collect::<Vec<u64>>());
+    assert_eq!(tree.search(&4001).unwrap(), Some("4001".to_string()));
+    assert_eq!(tree.stats().unwrap().key_bytes, 0);
+
+    // Byte arrays work too, as long as they fit
+    let mut tree = PBTree::<[u8; 32], u64>::with_inline_keys(&*arrays, 3).unwrap();
+    for i in 0..500u64 {
+        tree.insert(&[(i % 256) as u8; 32], &i).unwrap();
+    }
+    assert_eq!(tree.len(), 256);
+    assert_eq!(tree.search(&[7; 32]).unwrap(), Some(263));
+    assert_eq!(tree.iter().next().unwrap().unwrap(), ([0; 32], 256));
+}
+
+#[test]
+fn test_locate() {
+    // Binary search within a node finds what scanning its keys from the left does
+    let n = 2000u64;
+    let mut tree = PBTree::<String, u64, Vec<u8>>::with_storage(vec![], vec![], vec![], 8).unwrap();
+    tree.insert_batch((0..n).map(|i| (format!("key {:08}", i * 2), i))).unwrap();
+    for j in 0..2 * n + 1 {
+        let k = format!("key {:08}", j);
+        let mut x = tree.root.clone();
+        loop {
+            let mut i = 0;
+            while i < x.len as usize && tree.node_key(&x, i).unwrap() < k {
+                i += 1;
+            }
+            let present = i < x.len as usize && tree.node_key(&x, i).unwrap() == k;
+            assert_eq!(tree.locate(&x, &k).unwrap(), (i, present));
+            if present || x.leaf { break }
+            x = tree.node(x.children[i]).unwrap();
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn test_search_speed() {
+    let path = TestPath::new("test_search_speed");
+
+    // A benchmark rather than a test, so it only runs when asked for, with
+    // cargo test test_search_speed -- --ignored --nocapture
+    use std::time::SystemTime;
+
+    let n = 20000u64;
+    let mut tree = PBTree::<String, u64>::with_degree(&*path, 32).unwrap();
+    tree.insert_batch((0..n).map(|i| (format!("key {:08}", i), i))).unwrap();
+    // Keep every node cached, so the time goes into reading keys
+    tree.set_cache_size(2000);
+    let keys = (0..n).map(|i| format!("key {:08}", i * 7919 % n)).collect::<Vec<String>>();
+
+    // How lookups worked before: scanning the keys of each node from the left
+    let mut key_reads = 0;
     let now = SystemTime::now();
-    for i in 0..x {
-        let x = t.search(&i.to_string()).unwrap().unwrap();
-        //let y = test.search(&i.to_string()).unwrap().unwrap();
+    for k in keys.iter() {
+        let mut x = tree.root.clone();
+        let v = loop {
+            let mut i = 0;
+            let mut k_i = String::new();
+            while i < x.len as usize {
+                k_i = tree.node_key(&x, i).unwrap();
+                key_reads += 1;
+                if *k <= k_i { break }
+                i += 1;
+            }
+            if i < x.len as usize && *k == k_i { break Some(tree.read_value(x.values[i]).unwrap()) }
+            if x.leaf { break None }
+            x = tree.node(x.children[i]).unwrap();
+        };
+        assert!(v.is_some());
     }
-    match now.elapsed() {
-        Ok(a) => println!("time to search for {} string string pairs: {:?}", x, a),
-        Err(_) => panic!("Error measuring time.."),
-    };
+    let linear = now.elapsed().unwrap();
+
+    tree.set_key_cache_size(0);
+    let now = SystemTime::now();
+    for (i, k) in keys.iter().enumerate() {
+        assert_eq!(tree.search(k).unwrap(), Some(i as u64 * 7919 % n));
+    }
+    let binary = now.elapsed().unwrap();
+
+    // Keys near the root are compared against by every lookup, so caching them saves most reads
+    tree.set_key_cache_size(DEFAULT_KEY_CACHE_SIZE);
+    let now = SystemTime::now();
+    for (i, k) in keys.iter().enumerate() {
+        assert_eq!(tree.search(k).unwrap(), Some(i as u64 * 7919 % n));
+    }
+    let cached = now.elapsed().unwrap();
 
+    let seconds = |d: std::time::Duration| d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9f64;
+    println!("{} lookups in a tree of degree 32 with string keys:", n);
+    println!("linear scan: {:?} s, {} keys read per lookup", seconds(linear), key_reads as f64 / n as f64);
+    println!("binary search: {:?} s", seconds(binary));
+    println!("binary search with the key cache: {:?} s", seconds(cached));
 }
 
 #[test]
-fn test_file_buffer() {
-    use std::fs::{ File, OpenOptions };
-    use std::io::{ Error, Seek, SeekFrom, Read };
-    use std::marker::PhantomData;
+fn test_node_cache() {
+    let mut tree = PBTree::<u64, u64, Vec<u8>>::in_memory().unwrap();
+    // Without a cache every node is read from the storage
+    tree.set_cache_size(0);
+    for i in 0..2000u64 {
+        tree.insert(&i, &i).unwrap();
+    }
+    for i in 0..2000u64 {
+        assert_eq!(tree.search(&i).unwrap(), Some(i));
+    }
+    // A full cache that is made smaller gives up nodes until it fits
+    tree.set_cache_size(100);
+    for i in 0..2000u64 {
+        assert_eq!(tree.search(&i).unwrap(), Some(i));
+    }
+    tree.set_cache_size(3);
+    for i in 0..2000u64 {
+        assert_eq!(tree.remove(&i).unwrap(), Some(i));
+    }
+    assert!(tree.is_empty());
+}
+
+#[test]
+fn test_key_cache() {
+    use std::collections::BTreeMap;
+
+    // A cache too small to hold every key, so keys are dropped and read again
+    let mut tree = PBTree::<String, u64, Vec<u8>>::with_storage(vec![], vec![], vec![], 4).unwrap();
+    tree.set_key_cache_size(4096);
+    let mut model = BTreeMap::new();
+    for round in 0..10u64 {
+        for i in 0..1000u64 {
+            let k = format!("{:04}", (i * 7 + round * 3) % 1000);
+            tree.insert(&k, &(round * i)).unwrap();
+            model.insert(k, round * i);
+        }
+        // Removed keys free their space, and new keys of the same size take it over. A cached
+        // key must never be mistaken for whatever replaced it.
+        for i in 0..300u64 {
+            let k = format!("{:04}", (i * 11 + round) % 1000);
+            assert_eq!(tree.remove(&k).unwrap(), model.remove(&k));
+        }
+        for i in 0..300u64 {
+            let k = format!("{:04}", 1000 + round * 300 + i);
+            tree.insert(&k, &i).unwrap();
+            model.insert(k, i);
+        }
+        for (k, v) in model.iter() {
+            assert_eq!(tree.search(k).unwrap(), Some(*v));
+        }
+        assert!(!tree.contains_key(&"9999".to_string()).unwrap());
+    }
+    tree.set_key_cache_size(0);
+    for (k, v) in model.iter() {
+        assert_eq!(tree.search(k).unwrap(), Some(*v));
+    }
+}
+
+#[test]
+fn test_storage() {
+    use std::io::ErrorKind;
+
+    let path = TestPath::new("test_storage");
+
+    // Nothing here touches the disk
+    let mut tree = PBTree::<String, u64, Vec<u8>>::with_storage(vec![], vec![], vec![], 3).unwrap();
+    for i in 0..500u64 {
+        tree.insert(&i.to_string(), &i).unwrap();
+    }
+    for i in (0..500u64).filter(|i| i % 3 == 0) {
+        assert_eq!(tree.remove(&i.to_string()).unwrap(), Some(i));
+    }
+    assert_eq!(tree.len(), 333);
+    // A failed operation is rolled back in memory too
+    assert!(tree.insert_unique(&"1".to_string(), &0).is_err());
+    tree.sync().unwrap();
+
+    let PBTree { treefile, keyfile, valfile, .. } = tree;
+    let mut tree = PBTree::<String, u64, Vec<u8>>::open_storage(treefile, keyfile, valfile).unwrap();
+    assert_eq!(tree.len(), 333);
+    for i in 0..500u64 {
+        let expected = if i % 3 == 0 { None } else { Some(i) };
+        assert_eq!(tree.search(&i.to_string()).unwrap(), expected);
+    }
+    assert_eq!(tree.keys().count(), 333);
+
+    // Storage that is not a tree is rejected
+    assert_eq!(PBTree::<String, u64, Vec<u8>>::open_storage(vec![0; 8192], vec![], vec![]).err().unwrap().kind(),
+        ErrorKind::InvalidData);
+
+    // Bytes past the end of a BufFile read as zeros, even where a truncate left old ones behind
+    let file = std::fs::OpenOptions::new().read(true).write(true).truncate(true).create(true).open(&*path).unwrap();
+    let mut file = BufFile::new(file).unwrap();
+    file.write_at(0, &[7; 100]).unwrap();
+    file.truncate(50).unwrap();
+    let mut buf = [1; 20];
+    file.read_at(40, &mut buf).unwrap();
+    assert_eq!(&buf[.. 10], &[7; 10]);
+    assert_eq!(&buf[10 ..], &[0; 10]);
+    file.sync().unwrap();
+    let mut buf = [1; 20];
+    file.read_at(40, &mut buf).unwrap();
+    assert_eq!(&buf[.. 10], &[7; 10]);
+    assert_eq!(&buf[10 ..], &[0; 10]);
+    file.read_at(90, &mut buf).unwrap();
+    assert_eq!(buf, [0; 20]);
+    // Nor does the old tail stay in the file to be found when it is opened again
+    assert_eq!(std::fs::metadata(&*path).unwrap().len(), 50);
+    drop(file);
+    let file = std::fs::OpenOptions::new().read(true).write(true).open(&*path).unwrap();
+    assert_eq!(BufFile::new(file).unwrap().len(), 50);
+
+    // A record that claims to run past the end of the storage is an error, not zeros
     use raw_serde::*;
-    use file_buffer::*;
-    use node::{ Node, NodeCache };
-    use std::time::{ SystemTime };
-    use rand::Rng;
+    use storage::Reader;
+    let mut record = vec![];
+    100u64.raw_serialize(&mut record).unwrap();
+    record.extend_from_slice(&[b'x'; 10]);
+    let err = String::raw_deserialize(&mut Reader::new(&mut record, 0)).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
 
-    let now = SystemTime::now();
+#[test]
+fn test_mmap() {
+    use std::fs;
+    use std::mem;
 
-    let mut test_file = OpenOptions::new().read(true).write(true).truncate(true).create(true).open("test_file_Asrfsddg").unwrap();
-    let mut file = BufWriter::new(test_file);
+    let path = TestPath::new("test_mmap");
 
-    let mut rng = XorShiftRng::from_seed([0, 1, 377, 6712]);
+    {
+        let mut tree = PBTree::<String, u64, MmapFile>::with_degree(&*path, 4).unwrap();
+        for i in 0..3000u64 {
+            tree.insert(&i.to_string(), &i).unwrap();
+        }
+        for i in (0..3000u64).filter(|i| i % 2 == 0) {
+            assert_eq!(tree.remove(&i.to_string()).unwrap(), Some(i));
+        }
+        assert!(tree.insert_unique(&"1".to_string(), &0).is_err());
+    }
+    // The rest of the last chunk is given back once the tree is closed
+    assert!(fs::metadata(path.with(".val")).unwrap().len() < MMAP_CHUNK);
 
-    match now.elapsed() {
-        Ok(a) => println!("time for test_file_buffer: {:?}", a),
-        Err(_) => panic!("Error measuring time.."),
+    // The files are the same as those a BufFile writes, so either can open them
+    {
+        let mut tree = PBTree::<String, u64>::open(&*path).unwrap();
+        assert_eq!(tree.len(), 1500);
+        assert_eq!(tree.search(&"2999".to_string()).unwrap(), Some(2999));
+        tree.insert(&"3000".to_string(), &3000).unwrap();
+        tree.sync().unwrap();
+    }
+    {
+        // A file that was not grown has no trailer, and syncing must not write one over its data
+        let mut tree = PBTree::<String, u64, MmapFile>::open(&*path).unwrap();
+        tree.sync().unwrap();
+        for i in 0..3001u64 {
+            let expected = if i % 2 == 0 && i != 3000 { None } else { Some(i) };
+            assert_eq!(tree.search(&i.to_string()).unwrap(), expected);
+        }
+        assert!(tree.compact().unwrap() > 0);
+        assert_eq!(tree.values().count(), 1501);
+        tree.insert(&"3001".to_string(), &3001).unwrap();
+        tree.sync().unwrap();
+        tree.insert(&"3002".to_string(), &3002).unwrap();
+        // Without a proper close the files keep their last chunk
+        mem::forget(tree);
+    }
+    assert_eq!(fs::metadata(path.with(".val")).unwrap().len(), MMAP_CHUNK);
+
+    // Opening them again cuts them back to the data they held when they were last synced, and
+    // the write-ahead log brings back the rest
+    let mut tree = PBTree::<String, u64, MmapFile>::open(&*path).unwrap();
+    assert_eq!(tree.len(), 1503);
+    assert!(tree.treefile.len() < MMAP_CHUNK && tree.keyfile.len() < MMAP_CHUNK && tree.valfile.len() < MMAP_CHUNK);
+    assert_eq!(tree.search(&"3001".to_string()).unwrap(), Some(3001));
+    assert_eq!(tree.search(&"3002".to_string()).unwrap(), Some(3002));
+    assert_eq!(tree.search(&"2001".to_string()).unwrap(), Some(2001));
+}
+
+#[test]
+fn test_in_memory() {
+    use std::io::ErrorKind;
+
+    let path = TestPath::new("test_in_memory");
+    let copy = TestPath::new("test_in_memory_copy");
+
+    let mut tree = PBTree::<u64, String, Vec<u8>>::in_memory().unwrap();
+    for i in 0..2000u64 {
+        tree.insert(&i, &i.to_string()).unwrap();
+    }
+    for i in 0..500u64 {
+        tree.remove(&(i * 4)).unwrap();
+    }
+    tree.save_to(&*path).unwrap();
+    // The saved copy is independent of the tree in memory
+    tree.insert(&5000, &"5000".to_string()).unwrap();
+
+    let mut saved = PBTree::<u64, String>::open(&*path).unwrap();
+    assert_eq!(saved.len(), 1500);
+    for i in 0..2000u64 {
+        let expected = if i % 4 == 0 { None } else { Some(i.to_string()) };
+        assert_eq!(saved.search(&i).unwrap(), expected);
+    }
+    assert_eq!(saved.search(&5000).unwrap(), None);
+    assert_eq!(tree.len(), 1501);
+
+    // A tree can't be saved over its own files, but it can be saved elsewhere
+    assert_eq!(saved.save_to(&*path).unwrap_err().kind(), ErrorKind::InvalidInput);
+    saved.save_to(&*copy).unwrap();
+    assert_eq!(PBTree::<u64, String>::open(&*copy).unwrap().len(), 1500);
+}
+
+#[test]
+fn test_single_file() {
+    use std::fs;
+    use std::io::ErrorKind;
+
+    let path = TestPath::new("test_single_file");
+    let copy_path = TestPath::new("test_single_file_copy");
+    let bulk = TestPath::new("test_single_file_bulk");
+    let pages = TestPath::new("test_single_file_pages");
+    let garbage = TestPath::new("test_single_file_garbage");
+
+    let mut tree = PBTree::<u64, String, SingleFile>::new(&*path).unwrap();
+    for i in 0..20000u64 {
+        tree.insert(&i, &i.to_string()).unwrap();
+    }
+    for i in 0..5000u64 {
+        tree.remove(&(i * 4)).unwrap();
+    }
+    tree.sync().unwrap();
+    assert!(!::std::path::Path::new(&path.with(".tree")).exists());
+    drop(tree);
+
+    // Everything is found again through the page headers, and the three-file format is unaffected
+    let mut tree = PBTree::<u64, String, SingleFile>::open_or_create(&*path).unwrap();
+    assert_eq!(tree.len(), 15000);
+    for i in 0..20000u64 {
+        let expected = if i % 4 == 0 { None } else { Some(i.to_string()) };
+        assert_eq!(tree.search(&i).unwrap(), expected);
+    }
+    tree.compact().unwrap();
+    tree.save_to(&*copy_path).unwrap();
+    drop(tree);
+    assert_eq!(PBTree::<u64, String, SingleFile>::open(&*path).unwrap().len(), 15000);
+    let mut copy = PBTree::<u64, String>::open(&*copy_path).unwrap();
+    assert_eq!(copy.search(&7).unwrap(), Some("7".to_string()));
+
+    let tree = PBTree::<u64, String, SingleFile>::bulk_load(&*bulk, (0..1000u64).map(|i| (i, i.to_string()))).unwrap();
+    assert_eq!(tree.len(), 1000);
+    drop(tree);
+    assert_eq!(PBTree::<u64, String, SingleFile>::open(&*bulk).unwrap().len(), 1000);
+
+    // After a crash the log lengthens the regions past the ends in the header again
+    {
+        let mut tree = PBTree::<u64, String, SingleFile>::open(&*bulk).unwrap();
+        for i in 1000..3000u64 {
+            tree.insert(&i, &i.to_string()).unwrap();
+        }
+        ::std::mem::forget(tree);
+    }
+    let mut tree = PBTree::<u64, String, SingleFile>::open(&*bulk).unwrap();
+    assert_eq!(tree.len(), 3000);
+    assert_eq!(tree.search(&2999).unwrap(), Some("2999".to_string()));
+
+    // A tree in a single file can be moved to another thread
+    let mut tree = ::std::thread::spawn(move || {
+        tree.insert(&3000, &"3000".to_string()).unwrap();
+        tree
+    }).join().unwrap();
+    assert_eq!(tree.search(&3000).unwrap(), Some("3000".to_string()));
+    drop(tree);
+
+    // Pages past the end of a region are used by whichever region grows next, and are still
+    // free once the file has been opened again
+    {
+        let (mut nodes, mut keys, mut vals) = SingleFile::open_files(&pages, true, &Options::default()).unwrap();
+        vals.write_at(0, &vec![1; 10 * PAGE_SIZE as usize]).unwrap();
+        vals.truncate(100).unwrap();
+        keys.write_at(0, &vec![2; 9 * PAGE_SIZE as usize]).unwrap();
+        nodes.sync().unwrap();
+    }
+    let size = fs::metadata(pages.with(".pbt")).unwrap().len();
+    assert!(size <= CONTAINER_HEADER_SIZE + 11 * PAGE_SIZE);
+    {
+        let (mut nodes, mut keys, vals) = SingleFile::open_files(&pages, false, &Options::default()).unwrap();
+        assert_eq!(vals.len(), 100);
+        let mut buf = vec![0; 9 * PAGE_SIZE as usize];
+        keys.read_at(0, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 2));
+        keys.truncate(0).unwrap();
+        nodes.sync().unwrap();
+    }
+    {
+        let (mut nodes, _, mut vals) = SingleFile::open_files(&pages, false, &Options::default()).unwrap();
+        vals.write_at(100, &vec![3; 9 * PAGE_SIZE as usize]).unwrap();
+        nodes.sync().unwrap();
+    }
+    assert_eq!(fs::metadata(pages.with(".pbt")).unwrap().len(), size);
+
+    // Three-file trees are not single files
+    assert_eq!(PBTree::<u64, String, SingleFile>::open(&*copy_path).err().unwrap().kind(), ErrorKind::NotFound);
+    fs::write(garbage.with(".pbt"), vec![7u8; 5000]).unwrap();
+    assert_eq!(PBTree::<u64, String, SingleFile>::open(&*garbage).err().unwrap().kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_shared() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let path = TestPath::new("test_shared");
+    let mmap_path = TestPath::new("test_shared_mmap");
+
+    let mut tree = PBTree::<u64, String>::with_degree(&*path, 4).unwrap();
+    for i in 0..5000u64 {
+        tree.insert(&(i * 2), &i.to_string()).unwrap();
+    }
+    let tree = Arc::new(SharedPBTree::new(tree).unwrap());
+
+    // Readers only ever see even keys, which are never removed, while a writer adds odd keys
+    let readers: Vec<_> = (0..4u64).map(|r| {
+        let tree = tree.clone();
+        thread::spawn(move || {
+            for i in 0..5000u64 {
+                let k = (i * 7 + r * 1000) % 5000;
+                assert_eq!(tree.search(&(k * 2)).unwrap(), Some(k.to_string()));
+                assert!(tree.contains_key(&(k * 2)).unwrap());
+            }
+        })
+    }).collect();
+    for i in 0..2000u64 {
+        tree.insert(&(i * 2 + 1), &"odd".to_string()).unwrap();
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    // Nodes and keys cached before a write are not used once it has overwritten them
+    assert_eq!(tree.len().unwrap(), 7000);
+    for i in 0..2000u64 {
+        assert_eq!(tree.search(&(i * 2 + 1)).unwrap(), Some("odd".to_string()));
+        assert_eq!(tree.remove(&(i * 2 + 1)).unwrap(), Some("odd".to_string()));
+        assert_eq!(tree.search(&(i * 2 + 1)).unwrap(), None);
+    }
+    tree.write(|tree| tree.compact()).unwrap();
+    assert_eq!(tree.search(&4000).unwrap(), Some("2000".to_string()));
+
+    let mut tree = Arc::try_unwrap(tree).ok().unwrap().into_inner();
+    assert_eq!(tree.len(), 5000);
+    assert_eq!(tree.search(&9998).unwrap(), Some("4999".to_string()));
+
+    // Readers of mapped files map them again as they grow
+    let tree = PBTree::<u64, String, MmapFile>::with_degree(&*mmap_path, 4).unwrap();
+    let tree = Arc::new(SharedPBTree::new(tree).unwrap());
+    let reader = {
+        let tree = tree.clone();
+        thread::spawn(move || {
+            for _ in 0..200 {
+                let len = tree.len().unwrap();
+                for k in (0..len).rev().take(5) {
+                    assert_eq!(tree.search(&k).unwrap(), Some("x".repeat(10000)));
+                }
+                thread::yield_now();
+            }
+        })
     };
+    for i in 0..2000u64 {
+        tree.insert(&i, &"x".repeat(10000)).unwrap();
+    }
+    reader.join().unwrap();
+    for k in 1990..2000u64 {
+        assert_eq!(tree.search(&k).unwrap(), Some("x".repeat(10000)));
+    }
+    assert!(tree.write(|tree| Ok(tree.valfile.len())).unwrap() > MMAP_CHUNK);
+}
+
+#[test]
+fn test_shared_in_memory() {
+    use std::io::Error;
+    use std::sync::Arc;
+    use std::sync::atomic::{ AtomicBool, Ordering };
+    use std::thread;
+
+    // Trees in memory are shared through copies of their storage
+    let tree = Arc::new(SharedPBTree::new(PBTree::<u64, String, Vec<u8>>::in_memory().unwrap()).unwrap());
+    let reader = {
+        let tree = tree.clone();
+        thread::spawn(move || {
+            for _ in 0..200 {
+                let len = tree.len().unwrap();
+                for k in (0..len).rev().take(5) {
+                    assert_eq!(tree.search(&k).unwrap(), Some(k.to_string()));
+                }
+                thread::yield_now();
+            }
+        })
+    };
+    for i in 0..500u64 {
+        tree.insert(&i, &i.to_string()).unwrap();
+    }
+    reader.join().unwrap();
+    assert_eq!(tree.remove(&7).unwrap(), Some("7".to_string()));
+    assert_eq!(tree.search(&7).unwrap(), None);
+    assert_eq!(tree.len().unwrap(), 499);
+
+    // Storage that can be made to fail to publish
+    struct Flaky {
+        bytes: Vec<u8>,
+        fail: Arc<AtomicBool>
+    }
+    impl Storage for Flaky {
+        fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), Error> { self.bytes.read_at(pos, buf) }
+        fn write_at(&mut self, pos: u64, bytes: &[u8]) -> Result<(), Error> { self.bytes.write_at(pos, bytes) }
+        fn len(&self) -> u64 { self.bytes.len() as u64 }
+        fn truncate(&mut self, len: u64) -> Result<(), Error> { Storage::truncate(&mut self.bytes, len) }
+        fn sync(&mut self) -> Result<(), Error> { Ok(()) }
+    }
+    impl SharedStorage for Flaky {
+        type Reader = Vec<u8>;
+        fn reader(&self) -> Result<Vec<u8>, Error> { self.bytes.reader() }
+        fn publish(&mut self, reader: &mut Vec<u8>) -> Result<(), Error> {
+            if self.fail.load(Ordering::SeqCst) { return Err(Error::other("publish failed")) }
+            self.bytes.publish(reader)
+        }
+    }
+
+    // A write that can't be published is still made, but the shared tree can't be used again
+    let fail = Arc::new(AtomicBool::new(false));
+    let flaky = || Flaky { bytes: vec![], fail: fail.clone() };
+    let tree = PBTree::<u64, u64, Flaky>::with_storage(flaky(), flaky(), flaky(), 2).unwrap();
+    let tree = SharedPBTree::new(tree).unwrap();
+    for i in 0..100u64 {
+        tree.insert(&i, &i).unwrap();
+    }
+    fail.store(true, Ordering::SeqCst);
+    assert_eq!(tree.insert(&100, &100).unwrap(), None);
+    assert!(tree.search(&5).is_err());
+    assert!(tree.insert(&101, &101).is_err());
+    fail.store(false, Ordering::SeqCst);
+    assert!(tree.len().is_err());
+    let mut tree = tree.into_inner();
+    assert_eq!(tree.len(), 101);
+    assert_eq!(tree.search(&100).unwrap(), Some(100));
+    assert!(tree.insert(&101, &101).is_ok());
 }
@@ -0,0 +1,319 @@
+use std::cmp;
+use std::fs::File;
+use std::io::{ Error, Read, Seek, SeekFrom };
+use std::collections::HashMap;
+#[allow(unused_imports)]
+use raw_serde::*;
+use memmap::{ Mmap, MmapMut };
+use btree::Options;
+use storage::{ Storage, SharedStorage, SharedRead, FileStorage, open_three_files };
+
+/// The file behind an MmapFile is grown, and mapped again, this many bytes at a time.
+pub const MMAP_CHUNK: u64 = 16 * 1024 * 1024;
+
+/// Writes made while writes are pinned are kept in copies of the pages they touch.
+const PAGE_SIZE: u64 = 4096;
+
+/// Starts the trailer at the end of a file that has been grown past its data.
+const TRAILER_MAGIC: [u8; 8] = *b"PBTMMEND";
+
+/// The size of the trailer: TRAILER_MAGIC followed by the length of the data.
+const TRAILER_SIZE: u64 = 16;
+
+/// A file that is mapped into memory, so reading from it is a copy out of the mapping rather
+/// than a slab lookup. This suits trees that are read far more than they are written.
+///
+/// The file is grown MMAP_CHUNK bytes at a time as it is written past the end of the mapping,
+/// and cut back to its real length when the MmapFile is dropped. While it is grown, its last
+/// TRAILER_SIZE bytes record that length as of the last sync, so a file that was not closed
+/// properly is cut back to it when it is opened again.
+pub struct MmapFile {
+    file: File,
+    /// None while the file is empty, since an empty file can't be mapped.
+    map: Option<MmapMut>,
+    /// The file index that is the end of the file. The mapping may go past it.
+    pub end: u64,
+    /// While set, writes go to copies of the pages they touch rather than the mapping, where
+    /// the OS could write them to disk at any time.
+    pin_writes: bool,
+    /// The copies of the pages written to while pinning, by page number.
+    pages: HashMap<u64, Vec<u8>>
+}
+
+impl MmapFile {
+    /// Maps the whole of file, which must have been opened for reading and writing. If the
+    /// file was left grown past its data, it is cut back to the length its trailer records.
+    pub fn new(mut file: File) -> Result<MmapFile, Error> {
+        let metadata;
+        check!(file.metadata(), metadata);
+        let mut end = metadata.len();
+        if end >= MMAP_CHUNK && end % MMAP_CHUNK == 0 {
+            let mut trailer = [0u8; TRAILER_SIZE as usize];
+            check!(file.seek(SeekFrom::Start(end - TRAILER_SIZE)));
+            check!(file.read_exact(&mut trailer));
+            if trailer[.. 8] == TRAILER_MAGIC {
+                let recorded;
+                check!(u64::raw_deserialize(&mut &trailer[8 ..]), recorded);
+                if recorded <= end - TRAILER_SIZE {
+                    end = recorded;
+                    check!(file.set_len(end));
+                }
+            }
+        }
+        let mut mmap = MmapFile { file, map: None, end, pin_writes: false, pages: HashMap::new() };
+        check!(mmap.remap());
+        Ok(mmap)
+    }
+
+    /// The number of bytes that are mapped.
+    fn mapped(&self) -> u64 {
+        match self.map {
+            Some(ref map) => map.len() as u64,
+            None => 0
+        }
+    }
+
+    /// Maps the file again, e.g. after it has grown.
+    fn remap(&mut self) -> Result<(), Error> {
+        self.map = None;
+        let metadata;
+        check!(self.file.metadata(), metadata);
+        if metadata.len() > 0 {
+            // The mapping is only ever used through this MmapFile, which the tree owns
+            let map;
+            check!(unsafe { MmapMut::map_mut(&self.file) }, map);
+            self.map = Some(map);
+        }
+        Ok(())
+    }
+
+    /// Grows the file and the mapping, a chunk at a time, until at least len bytes are mapped
+    /// ahead of the trailer. The new trailer reaches the disk before anything is written over
+    /// the old one.
+    fn reserve(&mut self, len: u64) -> Result<(), Error> {
+        if len + TRAILER_SIZE <= self.mapped() { return Ok(()) }
+        let chunks = (len + TRAILER_SIZE).div_ceil(MMAP_CHUNK);
+        check!(self.file.set_len(chunks * MMAP_CHUNK));
+        check!(self.remap());
+        check!(self.write_trailer());
+        self.file.sync_all()
+    }
+
+    /// Records the end in the trailer, and waits for it to reach the disk. A file that has not
+    /// been grown since it was opened ends with its data, and has no trailer to write.
+    fn write_trailer(&mut self) -> Result<(), Error> {
+        let mapped = self.mapped();
+        if !mapped.is_multiple_of(MMAP_CHUNK) || mapped < self.end + TRAILER_SIZE { return Ok(()) }
+        if let Some(ref mut map) = self.map {
+            let mut trailer = TRAILER_MAGIC.to_vec();
+            check!(self.end.raw_serialize(&mut trailer));
+            let at = (mapped - TRAILER_SIZE) as usize;
+            map[at .. at + TRAILER_SIZE as usize].copy_from_slice(&trailer);
+            check!(map.flush_range(at, TRAILER_SIZE as usize));
+        }
+        Ok(())
+    }
+
+    /// Copies the mapped bytes at pos into buf. Bytes past the mapping read as zeros.
+    fn read_mapped(&self, pos: u64, buf: &mut [u8]) {
+        let start = cmp::min(pos, self.mapped()) as usize;
+        let end = cmp::min(pos + buf.len() as u64, self.mapped()) as usize;
+        let n = end - start;
+        if let Some(ref map) = self.map {
+            buf[.. n].copy_from_slice(&map[start .. end]);
+        }
+        for b in buf[n ..].iter_mut() { *b = 0; }
+    }
+
+    /// Copies the pages written while pinning into the mapping. Only the part of each page
+    /// ahead of the trailer is copied: nothing past the end needs to be kept, and the last page
+    /// may cover the trailer, or lie past the mapping if the end was moved back.
+    fn write_pages(&mut self) -> Result<(), Error> {
+        let end = self.end;
+        check!(self.reserve(end));
+        let limit = self.mapped().saturating_sub(TRAILER_SIZE) as usize;
+        let pages = ::std::mem::take(&mut self.pages);
+        if let Some(ref mut map) = self.map {
+            for (page, bytes) in pages {
+                let start = (page * PAGE_SIZE) as usize;
+                if start >= limit { continue }
+                let n = cmp::min(PAGE_SIZE as usize, limit - start);
+                map[start .. start + n].copy_from_slice(&bytes[.. n]);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MmapFile {
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), Error> {
+        if self.pages.is_empty() {
+            self.read_mapped(pos, buf);
+        } else {
+            // Read page by page, since some pages may have pinned copies
+            let mut done = 0;
+            while done < buf.len() {
+                let at = pos + done as u64;
+                let offset = (at % PAGE_SIZE) as usize;
+                let n = cmp::min(PAGE_SIZE as usize - offset, buf.len() - done);
+                match self.pages.get(&(at / PAGE_SIZE)) {
+                    Some(page) => buf[done .. done + n].copy_from_slice(&page[offset .. offset + n]),
+                    None => self.read_mapped(at, &mut buf[done .. done + n])
+                }
+                done += n;
+            }
+        }
+        // The mapping may hold old bytes past the end
+        if pos + buf.len() as u64 > self.end {
+            let from = self.end.saturating_sub(pos) as usize;
+            for b in buf[from ..].iter_mut() { *b = 0; }
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, pos: u64, bytes: &[u8]) -> Result<(), Error> {
+        let end = pos + bytes.len() as u64;
+        if self.pin_writes {
+            let mut done = 0;
+            while done < bytes.len() {
+                let at = pos + done as u64;
+                let page = at / PAGE_SIZE;
+                let offset = (at % PAGE_SIZE) as usize;
+                let n = cmp::min(PAGE_SIZE as usize - offset, bytes.len() - done);
+                if !self.pages.contains_key(&page) {
+                    let mut copy = vec![0u8; PAGE_SIZE as usize];
+                    self.read_mapped(page * PAGE_SIZE, &mut copy);
+                    self.pages.insert(page, copy);
+                }
+                if let Some(copy) = self.pages.get_mut(&page) {
+                    copy[offset .. offset + n].copy_from_slice(&bytes[done .. done + n]);
+                }
+                done += n;
+            }
+        } else {
+            check!(self.reserve(end));
+            if let Some(ref mut map) = self.map {
+                map[pos as usize .. end as usize].copy_from_slice(bytes);
+            }
+        }
+        if end > self.end { self.end = end; }
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.end
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        self.end = len;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        if let Some(ref map) = self.map {
+            check!(map.flush());
+        }
+        check!(self.write_trailer());
+        self.file.sync_data()
+    }
+
+    fn sync_range(&mut self, start: u64, end: u64) -> Result<(), Error> {
+        let end = cmp::min(end, self.mapped());
+        if start < end {
+            if let Some(ref map) = self.map {
+                check!(map.flush_range(start as usize, (end - start) as usize));
+            }
+        }
+        self.write_trailer()
+    }
+
+    fn pin_writes(&mut self, pin: bool) -> Result<(), Error> {
+        self.pin_writes = pin;
+        if pin { return Ok(()) }
+        self.write_pages()
+    }
+}
+
+/// Outside of an operation nothing is pinned, so the mapping is up to date, and publishing only
+/// maps the file again for readers once it has grown.
+impl SharedStorage for MmapFile {
+    type Reader = MmapReader;
+
+    fn reader(&self) -> Result<MmapReader, Error> {
+        let file;
+        check!(self.file.try_clone(), file);
+        let mut reader = MmapReader { file, map: None, end: 0 };
+        check!(self.publish_to(&mut reader));
+        Ok(reader)
+    }
+
+    fn publish(&mut self, reader: &mut MmapReader) -> Result<(), Error> {
+        self.publish_to(reader)
+    }
+}
+
+impl MmapFile {
+    fn publish_to(&self, reader: &mut MmapReader) -> Result<(), Error> {
+        let mapped = match reader.map {
+            Some(ref map) => map.len() as u64,
+            None => 0
+        };
+        if mapped != self.mapped() {
+            reader.map = None;
+            if self.mapped() > 0 {
+                // The file is only ever written to through the MmapFile, and only grows while
+                // readers can't see it
+                let map;
+                check!(unsafe { Mmap::map(&reader.file) }, map);
+                reader.map = Some(map);
+            }
+        }
+        reader.end = self.end;
+        Ok(())
+    }
+}
+
+/// Reads the file of an MmapFile through a mapping of its own, so many threads can read at once.
+pub struct MmapReader {
+    file: File,
+    map: Option<Mmap>,
+    /// The end of the file, as of when the MmapFile was last published.
+    pub end: u64
+}
+
+impl SharedRead for MmapReader {
+    fn read_shared(&self, pos: u64, buf: &mut [u8]) -> Result<(), Error> {
+        // The mapping may hold old bytes past the end
+        let n = match self.map {
+            Some(ref map) => {
+                let start = cmp::min(pos, self.end) as usize;
+                let end = cmp::min(pos + buf.len() as u64, self.end) as usize;
+                buf[.. end - start].copy_from_slice(&map[start .. end]);
+                end - start
+            },
+            None => 0
+        };
+        for b in buf[n ..].iter_mut() { *b = 0; }
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.end
+    }
+}
+
+impl FileStorage for MmapFile {
+    const EXTENSIONS: &'static [&'static str] = &[".tree", ".key", ".val"];
+
+    fn open_files(path: &str, create: bool, _options: &Options) -> Result<(Self, Self, Self), Error> {
+        open_three_files(path, create, MmapFile::new)
+    }
+}
+
+impl Drop for MmapFile {
+    fn drop(&mut self) {
+        // Unmap before giving back what is left of the last chunk
+        self.map = None;
+        let _ = self.file.set_len(self.end);
+    }
+}
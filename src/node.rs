@@ -1,36 +1,152 @@
-use std;
-use std::fs::File;
-use std::io::{ Read, Write, Error, Seek, SeekFrom };
+use std::io::{ Read, Write, Error };
 use std::collections::HashMap;
 use raw_serde::*;
 use btree::*;
 use std::cmp::Ordering;
 use std::io;
 use priority_queue::PriorityQueue;
+use storage::Storage;
 
-#[derive(RawSerialize, RawDeserialize, Copy, Clone, Debug)]
+/// The size of the slot a key takes up in a node when the key is kept in the key file and the
+/// node only holds its location.
+pub const KEY_LOC_SIZE: u64 = 8;
+
+/// Size of a serialized Node of minimum degree t with key slots of key_size bytes: parent, loc
+/// and len, the keys, the value locations, the children, and the leaf flag.
+pub fn node_size(t: u64, key_size: u64) -> u64 {
+    8 * 3 + (key_size + 8) * (2 * t - 1) + 8 * 2 * t + 1
+}
+
+/// A node of a tree with minimum degree t. keys holds 2t - 1 slots of key_size bytes, values
+/// 2t - 1 locations and children 2t, of which only the first len (and len + 1) are in use, so
+/// every node of a tree serializes to the same number of bytes.
+///
+/// A key slot holds the serialized key itself in trees with inline keys, and the location of
+/// the key in the key file, serialized like any u64, in other trees.
+#[derive(Clone, Debug)]
 pub struct Node {
     pub parent: u64,
     pub loc: u64,
     pub len: u64,
-    pub keys: [u64; NUM_KEYS],
-    pub values: [u64; NUM_KEYS],
-    pub children: [u64; NUM_CHILDREN],
+    pub keys: Vec<u8>,
+    pub key_size: usize,
+    pub values: Vec<u64>,
+    pub children: Vec<u64>,
     pub leaf: bool
 }
 
 impl Node {
-    pub fn new() -> Node {
+    pub fn new(t: u64, key_size: u64) -> Node {
+        let num_keys = (2 * t - 1) as usize;
         Node {
             parent: NONE,
             len: 0,
             loc: 0,
-            keys: [0; NUM_KEYS],
-            values: [0; NUM_KEYS],
-            children: [0; NUM_CHILDREN],
+            keys: vec![0; num_keys * key_size as usize],
+            key_size: key_size as usize,
+            values: vec![0; num_keys],
+            children: vec![0; num_keys + 1],
             leaf: true
         }
     }
+
+    /// Reads a node of a tree with minimum degree t and key slots of key_size bytes.
+    pub fn read_from(from: &mut dyn Read, t: u64, key_size: u64) -> Result<Node, Error> {
+        let parent;
+        let loc;
+        let len;
+        check!(u64::raw_deserialize(from), parent);
+        check!(u64::raw_deserialize(from), loc);
+        check!(u64::raw_deserialize(from), len);
+        let num_keys = (2 * t - 1) as usize;
+        let mut keys = vec![0; num_keys * key_size as usize];
+        check!(from.read_exact(&mut keys));
+        let mut locations = vec![];
+        for _ in 0 .. 4 * t - 1 {
+            let x;
+            check!(u64::raw_deserialize(from), x);
+            locations.push(x);
+        }
+        let leaf;
+        check!(bool::raw_deserialize(from), leaf);
+
+        let children = locations.split_off(num_keys);
+        let node = Node { parent, loc, len, keys, key_size: key_size as usize, values: locations, children, leaf };
+        Ok(node)
+    }
+
+    /// Reads the node at pos in storage, all in one read.
+    pub fn read_at<S: Storage + ?Sized>(storage: &mut S, pos: u64, t: u64, key_size: u64) -> Result<Node, Error> {
+        let mut buf = vec![0; node_size(t, key_size) as usize];
+        check!(storage.read_at(pos, &mut buf));
+        Node::read_from(&mut &buf[..], t, key_size)
+    }
+
+    /// The slot of key i.
+    #[inline(always)]
+    pub fn key(&self, i: usize) -> &[u8] {
+        &self.keys[i * self.key_size .. (i + 1) * self.key_size]
+    }
+
+    /// The location in the key file of key i, for trees whose keys are not inline.
+    #[inline(always)]
+    pub fn key_loc(&self, i: usize) -> u64 {
+        slot_loc(self.key(i))
+    }
+
+    /// The slot of key i and the location of its value.
+    pub fn entry(&self, i: usize) -> (Vec<u8>, u64) {
+        (self.key(i).to_vec(), self.values[i])
+    }
+
+    /// Sets the slot of key i and the location of its value.
+    pub fn set_entry(&mut self, i: usize, key: &[u8], value: u64) {
+        self.keys[i * self.key_size .. (i + 1) * self.key_size].copy_from_slice(key);
+        self.values[i] = value;
+    }
+
+    /// Copies n entries of the node starting at from to start at to instead. The ranges may
+    /// overlap.
+    pub fn move_entries(&mut self, from: usize, to: usize, n: usize) {
+        let size = self.key_size;
+        self.keys.copy_within(from * size .. (from + n) * size, to * size);
+        self.values.copy_within(from .. from + n, to);
+    }
+
+    /// Copies n entries of src starting at from into the node starting at to.
+    pub fn copy_entries(&mut self, to: usize, src: &Node, from: usize, n: usize) {
+        let size = self.key_size;
+        self.keys[to * size .. (to + n) * size].copy_from_slice(&src.keys[from * size .. (from + n) * size]);
+        self.values[to .. to + n].copy_from_slice(&src.values[from .. from + n]);
+    }
+}
+
+/// The slot of a key kept at loc in the key file.
+pub fn loc_slot(loc: u64) -> Vec<u8> {
+    loc.to_ne_bytes().to_vec()
+}
+
+/// The location in the key file held by a key slot.
+pub fn slot_loc(slot: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(slot);
+    u64::from_ne_bytes(bytes)
+}
+
+/// Unlike a derived implementation, the vectors are written without a length, which is implied
+/// by the degree of the tree and the size of its key slots.
+impl RawSerialize for Node {
+    fn raw_serialize(&self, to: &mut dyn Write) -> Result<u64, Error> {
+        check!(self.parent.raw_serialize(to));
+        check!(self.loc.raw_serialize(to));
+        check!(self.len.raw_serialize(to));
+        check!(to.write_all(&self.keys));
+        for x in self.values.iter().chain(self.children.iter()) {
+            check!(x.raw_serialize(to));
+        }
+        check!(self.leaf.raw_serialize(to));
+        Ok(8 * 3 + self.keys.len() as u64 + 8 * (self.values.len() + self.children.len()) as u64 + 1)
+    }
 }
 
 struct Freq {
@@ -42,7 +158,7 @@ impl Freq {
     pub fn new(loc: u64) -> Freq {
         Freq {
             freq: 1,
-            loc: loc
+            loc
         }
     }
 }
@@ -55,7 +171,7 @@ impl Ord for Freq {
 
 impl PartialOrd for Freq {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(other.freq.cmp(&self.freq))
+        Some(self.cmp(other))
     }
 }
 
@@ -70,20 +186,26 @@ impl Eq for Freq {}
 
 pub struct NodeCache {
     pub size: usize,
+    /// The minimum degree of the tree the nodes belong to.
+    t: u64,
+    /// The size of the key slots of the nodes.
+    key_size: u64,
     freqs: PriorityQueue<Freq>,
     nodes: HashMap<u64, Node>
 }
 
 impl NodeCache {
-    pub fn new(size: usize) -> Self {
+    pub fn new(size: usize, t: u64, key_size: u64) -> Self {
         NodeCache {
             size,
+            t,
+            key_size,
             freqs: PriorityQueue::new(),
             nodes: HashMap::<u64, Node>::new()
         }
     }
 
-    pub fn get<F: Read + Write + Seek>(&mut self, node_loc: u64, file: &mut F) -> Result<Node, io::Error> {
+    pub fn get<S: Storage + ?Sized>(&mut self, node_loc: u64, file: &mut S) -> Result<Node, io::Error> {
         if self.nodes.contains_key(&node_loc) {
             if self.freqs.update_key(Freq::new(node_loc), |x| x.freq += 1).is_err() {
                 unreachable!();
@@ -91,16 +213,18 @@ impl NodeCache {
             Ok(self.nodes[&node_loc].clone())
         } else {
             let node;
-            check!(NodeCache::read_node(node_loc, file), node);
-            if self.nodes.len() < self.size {
-                self.nodes.insert(node_loc, node);
-                self.freqs.push(Freq::new(node_loc));
-            } else {
-                let lfu = self.freqs.poll().unwrap();
-                self.nodes.remove(&lfu.loc);
-                self.freqs.push(Freq::new(node_loc));
-                self.nodes.insert(node_loc, node);
+            check!(Node::read_at(file, node_loc, self.t, self.key_size), node);
+            // A cache of size 0 keeps nothing
+            if self.size == 0 { return Ok(node) }
+            // Make room, evicting more than one node if the cache has been made smaller
+            while self.nodes.len() >= self.size {
+                match self.freqs.poll() {
+                    Some(lfu) => { self.nodes.remove(&lfu.loc); },
+                    None => break
+                }
             }
+            self.freqs.push(Freq::new(node_loc));
+            self.nodes.insert(node_loc, node.clone());
             Ok(node)
         }
     }
@@ -111,9 +235,17 @@ impl NodeCache {
         }
     }
 
-    fn read_node<F: Read + Write + Seek>(pos: u64, file: &mut F) -> Result<Node, Error> {
-        check!(file.seek(SeekFrom::Start(pos)));
-        Node::raw_deserialize(file)
+    /// Drops every node from the cache.
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.freqs = PriorityQueue::new();
+    }
+
+    /// Drops a node from the cache, e.g. because it was freed and must not be served again.
+    pub fn remove(&mut self, node_loc: u64) {
+        if self.nodes.remove(&node_loc).is_some() {
+            self.freqs.remove(&Freq::new(node_loc));
+        }
     }
 
 }
@@ -84,7 +84,7 @@ impl<T> PriorityQueue<T> where T: Ord {
 
     // Applies a function to a key and moves it to the proper position in the heap.
     pub fn update_key<F>(&mut self, key: T, apply: F) -> Result<(), ()>
-        where F: Fn(&mut T) -> () {
+        where F: Fn(&mut T) {
         let ind = self.search(&key);
         match ind {
             Some(ind) => {
@@ -111,15 +111,12 @@ impl<T> PriorityQueue<T> where T: Ord {
 
     /// Checks if the queue contains the element
     pub fn contains(&self, item: &T) -> bool {
-        match self.search(item) {
-            Some(_) => true,
-            None => false
-        }
+        self.search(item).is_some()
     }
 
     /// Removes an element to the heap and ensures it is still a heap; if not it makes it so.
     pub fn poll(&mut self) -> Option<T> {
-        if self.arr.len() == 0 {
+        if self.arr.is_empty() {
             None
         } else if self.arr.len() == 1 {
             self.arr.pop()
@@ -132,8 +129,27 @@ impl<T> PriorityQueue<T> where T: Ord {
         }
     }
 
+    /// Removes the element equal to item from the heap, if present, and returns it.
+    pub fn remove(&mut self, item: &T) -> Option<T> {
+        let ind = self.arr.iter().position(|x| x == item)?;
+        let last = self.arr.len() - 1;
+        self.arr.swap(ind, last);
+        let result = self.arr.pop();
+        if ind < self.arr.len() {
+            // The element moved into ind may belong either above or below it
+            let mut index = ind;
+            while index != 0 && self.arr[index] < self.arr[Self::parent(index)] {
+                let parent = Self::parent(index);
+                self.arr.swap(index, parent);
+                index = parent;
+            }
+            self.adjust_after_decrease(index);
+        }
+        result
+    }
+
     /// Returns true of the queue is empty, otherwise false.
     pub fn empty(&self) -> bool {
-        self.arr.len() == 0
+        self.arr.is_empty()
     }
 }
@@ -0,0 +1,363 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::io::Error;
+use std::sync::{ Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard };
+use std::sync::atomic::{ self, AtomicBool };
+use raw_serde::*;
+use type_tag::TypeTag;
+use btree::{ PBTree, NONE };
+use file_buffer::BufFile;
+use key_cache::{ KeyCache, DEFAULT_KEY_CACHE_SIZE };
+use node::{ Node, node_size };
+use storage::{ SharedStorage, SharedRead, SharedReader };
+use wal::{ TREE_FILE, KEY_FILE };
+
+/// The number of bytes of nodes a SharedPBTree keeps by default.
+pub const DEFAULT_SHARED_NODE_CACHE_SIZE: usize = 8 << 20;
+
+/// The number of independently locked parts the caches of a SharedPBTree are split into, so
+/// threads rarely wait on each other for them.
+const SHARDS: usize = 16;
+
+/// A cache that many threads can use at once. Each location belongs to one of SHARDS KeyCaches,
+/// each with its own lock and an equal share of the limit.
+struct SharedCache<T> {
+    shards: Vec<Mutex<KeyCache<T>>>
+}
+
+impl<T> SharedCache<T> {
+    fn new(limit: usize) -> Self {
+        SharedCache { shards: (0 .. SHARDS).map(|_| Mutex::new(KeyCache::new(limit / SHARDS))).collect() }
+    }
+
+    fn shard<'a>(&'a self, pos: u64) -> MutexGuard<'a, KeyCache<T>> {
+        // Nodes and records are laid out at regular intervals, so mix the bits before choosing
+        let i = (pos.wrapping_mul(0x9E3779B97F4A7C15) >> 32) as usize % SHARDS;
+        // A cache can't be left in a state that is unsafe to use, even by a panic
+        match self.shards[i].lock() {
+            Ok(shard) => shard,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Calls f with the value at pos, if it is cached.
+    fn get<R, F: FnOnce(&T) -> R>(&self, pos: u64, f: F) -> Option<R> {
+        self.shard(pos).get(pos).map(f)
+    }
+
+    fn insert(&self, pos: u64, value: T, serialized_size: usize) {
+        self.shard(pos).insert(pos, value, serialized_size)
+    }
+
+    fn remove(&self, pos: u64) {
+        self.shard(pos).remove(pos)
+    }
+
+    fn clear(&self) {
+        for shard in self.shards.iter() {
+            match shard.lock() {
+                Ok(mut shard) => shard.clear(),
+                Err(poisoned) => poisoned.into_inner().clear()
+            }
+        }
+    }
+}
+
+/// A PBTree that can be shared between threads, e.g. through an Arc. Any number of threads can
+/// search it at once, and while one thread writes to it.
+///
+/// Writes go to the tree itself, which only one thread uses at a time. Searches read what the
+/// tree has published to its storage instead, through readers of their own and a copy of the
+/// root, and keep the nodes and keys they read in caches that every thread can use at once. A
+/// write is published once it is complete: it is written back to the storage, the cached nodes
+/// and keys it overwrote are dropped, and the new root is swapped in. This waits for the
+/// searches in progress, and searches wait for it, but it takes no more than writing back what
+/// the write changed; the write itself, and any checkpoint that follows it, go on alongside them.
+///
+/// If a write can't be published, searches would no longer see what the tree holds, so the
+/// SharedPBTree is poisoned: everything but into_inner fails from then on. The write itself is
+/// still in the tree, and in its log.
+///
+/// The storage must be SharedStorage, so trees kept in a SingleFile can't be shared.
+pub struct SharedPBTree<K, V, S: SharedStorage = BufFile> {
+    tree: Mutex<PBTree<K, V, S>>,
+    /// What searches see: the tree as of the last write to be published.
+    published: RwLock<Published<S::Reader>>,
+    /// Set once a write could not be published.
+    poisoned: AtomicBool,
+    nodes: SharedCache<Arc<Node>>,
+    keys: SharedCache<K>
+}
+
+/// The part of a tree that searches need, as of when it was published.
+struct Published<R> {
+    root: Node,
+    len: u64,
+    t: u64,
+    key_size: u64,
+    inline_keys: bool,
+    treefile: R,
+    keyfile: R,
+    valfile: R
+}
+
+impl<R> Published<R> {
+    /// Publishes everything written to the files of tree to new readers of them, along with a
+    /// copy of its root.
+    fn new<K, V, S: SharedStorage<Reader=R>>(tree: &mut PBTree<K, V, S>) -> Result<Self, Error>
+        where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+                V: RawSerialize + RawDeserialize + TypeTag + Debug {
+        let (mut treefile, mut keyfile, mut valfile);
+        check!(tree.treefile.reader(), treefile);
+        check!(tree.keyfile.reader(), keyfile);
+        check!(tree.valfile.reader(), valfile);
+        check!(tree.treefile.publish(&mut treefile));
+        check!(tree.keyfile.publish(&mut keyfile));
+        check!(tree.valfile.publish(&mut valfile));
+        Ok(Published {
+            root: tree.root.clone(),
+            len: tree.len(),
+            t: tree.degree(),
+            key_size: tree.key_size(),
+            inline_keys: tree.inline_keys(),
+            treefile,
+            keyfile,
+            valfile
+        })
+    }
+}
+
+impl<K, V, S: SharedStorage> SharedPBTree<K, V, S>
+    where   K: RawSerialize + RawDeserialize + TypeTag + Eq + Ord + Debug,
+            V: RawSerialize + RawDeserialize + TypeTag + Debug {
+
+    /// Shares tree, with caches of the default sizes.
+    pub fn new(tree: PBTree<K, V, S>) -> Result<Self, Error> {
+        Self::with_cache_sizes(tree, DEFAULT_SHARED_NODE_CACHE_SIZE, DEFAULT_KEY_CACHE_SIZE)
+    }
+
+    /// Shares tree, keeping up to about node_bytes bytes of nodes and key_bytes bytes of keys
+    /// for searches.
+    pub fn with_cache_sizes(mut tree: PBTree<K, V, S>, node_bytes: usize, key_bytes: usize) -> Result<Self, Error> {
+        let published;
+        check!(Published::new(&mut tree), published);
+        Ok(SharedPBTree {
+            tree: Mutex::new(tree),
+            published: RwLock::new(published),
+            poisoned: AtomicBool::new(false),
+            nodes: SharedCache::new(node_bytes),
+            keys: SharedCache::new(key_bytes)
+        })
+    }
+
+    /// Stops sharing the tree and returns it.
+    pub fn into_inner(self) -> PBTree<K, V, S> {
+        match self.tree.into_inner() {
+            Ok(tree) => tree,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// The number of entries in the tree.
+    pub fn len(&self) -> Result<u64, Error> {
+        let published;
+        check!(self.read(), published);
+        Ok(published.len)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        let len;
+        check!(self.len(), len);
+        Ok(len == 0)
+    }
+
+    pub fn search(&self, k: &K) -> Result<Option<V>, Error> {
+        let published;
+        check!(self.read(), published);
+        let found;
+        check!(self.find_value(&published, k), found);
+        match found {
+            Some(v_loc) => V::raw_deserialize(&mut SharedReader::new(&published.valfile, v_loc)).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    pub fn contains_key(&self, k: &K) -> Result<bool, Error> {
+        let published;
+        check!(self.read(), published);
+        let found;
+        check!(self.find_value(&published, k), found);
+        Ok(found.is_some())
+    }
+
+    /// Inserts an entry as PBTree::insert does. Searches go on while it does, and see the entry
+    /// once it returns.
+    pub fn insert(&self, k: &K, v: &V) -> Result<Option<V>, Error> {
+        self.modify(|tree| tree.insert(k, v))
+    }
+
+    /// Removes an entry as PBTree::remove does. Searches go on while it does, and stop seeing
+    /// the entry once it returns.
+    pub fn remove(&self, k: &K) -> Result<Option<V>, Error> {
+        self.modify(|tree| tree.remove(k))
+    }
+
+    /// Calls f with the tree itself, once no search is in progress, for anything else that
+    /// modifies or syncs it. Searches wait for f to return, and start again with empty caches.
+    pub fn write<R, F: FnOnce(&mut PBTree<K, V, S>) -> Result<R, Error>>(&self, f: F) -> Result<R, Error> {
+        let mut tree;
+        check!(self.lock(), tree);
+        let mut published;
+        check!(self.publishing(), published);
+        let res = f(&mut tree);
+        self.nodes.clear();
+        self.keys.clear();
+        // f may have replaced the files, e.g. by compacting the tree
+        match Published::new(&mut tree) {
+            Ok(refreshed) => *published = refreshed,
+            Err(e) => {
+                self.poisoned.store(true, atomic::Ordering::SeqCst);
+                return Err(e)
+            }
+        }
+        res
+    }
+
+    /// Calls f, which must do a single operation on the tree, while searches go on, then
+    /// publishes what it wrote. Once f has returned its writes are let through to the storage
+    /// whatever happens, and its result is returned even if publishing fails, since the
+    /// operation is done by then.
+    fn modify<R, F: FnOnce(&mut PBTree<K, V, S>) -> Result<R, Error>>(&self, f: F) -> Result<R, Error> {
+        let mut tree;
+        check!(self.lock(), tree);
+        tree.hold_writes = true;
+        let res = f(&mut tree);
+        tree.hold_writes = false;
+        if self.publish(&mut tree).is_err() {
+            // The writes are still pinned if searches couldn't be kept out
+            let _ = tree.unpin_writes();
+            self.poisoned.store(true, atomic::Ordering::SeqCst);
+            return res
+        }
+        check!(tree.checkpoint());
+        res
+    }
+
+    /// Lets the writes of the last operation on tree through to the storage and publishes
+    /// them, dropping what they overwrote from the caches. Writes are only let through once
+    /// searches are kept out, since some storage shows them to readers straight away.
+    fn publish(&self, tree: &mut PBTree<K, V, S>) -> Result<(), Error> {
+        let mut published;
+        check!(self.publishing(), published);
+        check!(tree.unpin_writes());
+        for (file_id, pos) in tree.last_writes() {
+            match file_id {
+                TREE_FILE => self.nodes.remove(pos),
+                KEY_FILE => self.keys.remove(pos),
+                // Values are never cached
+                _ => ()
+            }
+        }
+        check!(tree.treefile.publish(&mut published.treefile));
+        check!(tree.keyfile.publish(&mut published.keyfile));
+        check!(tree.valfile.publish(&mut published.valfile));
+        published.root = tree.root.clone();
+        published.len = tree.len();
+        Ok(())
+    }
+
+    /// Fails once a write could not be published.
+    fn check_usable(&self) -> Result<(), Error> {
+        if self.poisoned.load(atomic::Ordering::SeqCst) {
+            return Err(Error::other(
+                "a write to the shared tree could not be published; take it back with into_inner, or open it again"));
+        }
+        Ok(())
+    }
+
+    fn read<'a>(&'a self) -> Result<RwLockReadGuard<'a, Published<S::Reader>>, Error> {
+        check!(self.check_usable());
+        self.published.read().map_err(|_| poisoned())
+    }
+
+    /// Keeps searches out while something is published.
+    fn publishing<'a>(&'a self) -> Result<RwLockWriteGuard<'a, Published<S::Reader>>, Error> {
+        self.published.write().map_err(|_| poisoned())
+    }
+
+    fn lock<'a>(&'a self) -> Result<MutexGuard<'a, PBTree<K, V, S>>, Error> {
+        check!(self.check_usable());
+        self.tree.lock().map_err(|_| poisoned())
+    }
+
+    /// The location of the value of k, if it is in the tree.
+    fn find_value(&self, published: &Published<S::Reader>, k: &K) -> Result<Option<u64>, Error> {
+        let step;
+        check!(self.find(published, &published.root, k), step);
+        let (mut found, mut next) = step;
+        while found.is_none() && next != NONE {
+            let x;
+            check!(self.node(published, next), x);
+            let step;
+            check!(self.find(published, &x, k), step);
+            found = step.0;
+            next = step.1;
+        }
+        Ok(found)
+    }
+
+    /// Looks for k in x. Returns the location of its value if it is there, and otherwise the
+    /// child to look in next, NONE if x is a leaf.
+    fn find(&self, published: &Published<S::Reader>, x: &Node, k: &K) -> Result<(Option<u64>, u64), Error> {
+        let (mut lo, mut hi) = (0, x.len as usize);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let ord;
+            check!(self.cmp_key(published, k, x, mid), ord);
+            match ord {
+                Ordering::Less => hi = mid,
+                Ordering::Greater => lo = mid + 1,
+                Ordering::Equal => return Ok((Some(x.values[mid]), NONE))
+            }
+        }
+        Ok((None, if x.leaf { NONE } else { x.children[lo] }))
+    }
+
+    /// How k compares to key i of node x, as PBTree::cmp_key but with the shared key cache.
+    fn cmp_key(&self, published: &Published<S::Reader>, k: &K, x: &Node, i: usize) -> Result<Ordering, Error> {
+        if published.inline_keys {
+            let k_i;
+            check!(K::raw_deserialize(&mut &x.key(i)[..]), k_i);
+            return Ok(k.cmp(&k_i))
+        }
+        let pos = x.key_loc(i);
+        if let Some(ord) = self.keys.get(pos, |k_i| k.cmp(k_i)) {
+            return Ok(ord)
+        }
+        let mut reader = SharedReader::new(&published.keyfile, pos);
+        let k_i;
+        check!(K::raw_deserialize(&mut reader), k_i);
+        let ord = k.cmp(&k_i);
+        let size = reader.pos - pos;
+        self.keys.insert(pos, k_i, size as usize);
+        Ok(ord)
+    }
+
+    /// The node at pos, from the shared node cache if it is there.
+    fn node(&self, published: &Published<S::Reader>, pos: u64) -> Result<Arc<Node>, Error> {
+        if let Some(node) = self.nodes.get(pos, |node| node.clone()) {
+            return Ok(node)
+        }
+        let mut buf = vec![0; node_size(published.t, published.key_size) as usize];
+        check!(published.treefile.read_shared(pos, &mut buf));
+        let node;
+        check!(Node::read_from(&mut &buf[..], published.t, published.key_size), node);
+        let node = Arc::new(node);
+        self.nodes.insert(pos, node.clone(), buf.len());
+        Ok(node)
+    }
+}
+
+fn poisoned() -> Error {
+    Error::other("a thread panicked while writing to the tree")
+}
@@ -0,0 +1,388 @@
+use std::cmp;
+use std::mem;
+use std::collections::BTreeSet;
+use std::fs::{ File, OpenOptions };
+use std::io::{ Error, ErrorKind, Read, Seek, SeekFrom, Write };
+use std::sync::{ Arc, Mutex, MutexGuard };
+use raw_serde::*;
+use btree::{ Options, NONE };
+use header::HEADER_SIZE;
+use file_buffer::BufFile;
+use storage::{ Storage, FileStorage, Reader };
+
+/// Identifies a single-file tree. Always the first 8 bytes of it.
+pub const CONTAINER_MAGIC: [u8; 8] = *b"PBTFILE\0";
+
+/// The version of the layout of single files: their header and pages. What the regions hold is
+/// covered by FORMAT_VERSION, as it is for the three files of other trees.
+pub const CONTAINER_VERSION: u64 = 1;
+
+/// Bytes reserved for the header at the start of a single file. The first page comes right after.
+pub const CONTAINER_HEADER_SIZE: u64 = 4096;
+
+/// The size of the pages a single file is divided into, counting the header of each page.
+pub const PAGE_SIZE: u64 = 64 * 1024;
+
+/// Bytes at the start of each page that name the region it belongs to and its place in the region.
+const PAGE_HEADER_SIZE: u64 = 16;
+
+/// The bytes of a region that each page holds.
+const PAGE_DATA: u64 = PAGE_SIZE - PAGE_HEADER_SIZE;
+
+/// The first thing in every single file.
+#[derive(RawSerialize, RawDeserialize, Clone, Debug)]
+struct ContainerHeader {
+    magic: [u8; 8],
+    version: u64,
+    page_size: u64,
+    /// The lengths of the node, key and value regions when the file was last synced. Writes
+    /// since then are in the write-ahead log, and lengthen the regions again when replayed.
+    tree_len: u64,
+    key_len: u64,
+    val_len: u64
+}
+
+/// A file holding the nodes, keys and values of a tree in three regions, each made up of pages
+/// that are handed out as the region grows, so the regions can all keep growing. Every page
+/// starts with the number of its region (1 for nodes, 2 for keys, 3 for values, 0 for a page
+/// that is not in use) and its index in the region, so the pages can be found again by reading
+/// the page headers when the file is opened.
+///
+/// Pages wholly past the end of a region, e.g. those of an operation that was rolled back, are
+/// handed back and can be used by any region. Their headers are cleared when the file is next
+/// synced. Until then the file may hold two pages with the same place in a region, but only past
+/// the end recorded in the header, which the write-ahead log writes again anyway.
+struct Container {
+    file: BufFile,
+    /// The page holding each page of each region, NONE for one that was never written to.
+    pages: [Vec<u64>; 3],
+    /// The number of pages in the file.
+    num_pages: u64,
+    /// The pages that are not in use, which are handed out before the file is grown.
+    free: BTreeSet<u64>,
+    /// The free pages whose headers still name the region they were last in.
+    uncleared: BTreeSet<u64>,
+    /// The lengths of the regions.
+    ends: [u64; 3],
+    /// Whether anything has changed since the last sync. A tree syncs each of its regions in
+    /// turn, and only the first of them needs to sync the file.
+    dirty: bool
+}
+
+impl Container {
+    fn create(_file: File, options: &Options) -> Result<Container, Error> {
+        let file;
+        check!(buffer(_file, options), file);
+        let mut container = Container {
+            file,
+            pages: [vec![], vec![], vec![]],
+            num_pages: 0,
+            free: BTreeSet::new(),
+            uncleared: BTreeSet::new(),
+            ends: [0; 3],
+            dirty: true
+        };
+        check!(container.write_header());
+        Ok(container)
+    }
+
+    /// Opens the single file at name, finding the pages of each region.
+    fn open(_file: File, name: &str, options: &Options) -> Result<Container, Error> {
+        let mut file;
+        check!(buffer(_file, options), file);
+        if file.end < CONTAINER_HEADER_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{} is too short ({} bytes) to contain a tree", name, file.end)));
+        }
+        let header;
+        check!(ContainerHeader::raw_deserialize(&mut Reader::new(&mut file, 0)), header);
+        if header.magic != CONTAINER_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} is not a single-file tree", name)));
+        }
+        if header.version != CONTAINER_VERSION || header.page_size != PAGE_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{} has layout version {} with {} byte pages, but only version {} with {} byte pages is supported",
+                    name, header.version, header.page_size, CONTAINER_VERSION, PAGE_SIZE)));
+        }
+
+        let ends = [header.tree_len, header.key_len, header.val_len];
+        let num_pages = (file.end - CONTAINER_HEADER_SIZE).div_ceil(PAGE_SIZE);
+        let mut pages = [vec![], vec![], vec![]];
+        let (mut free, mut uncleared) = (BTreeSet::new(), BTreeSet::new());
+        for page in 0 .. num_pages {
+            let mut buf = [0u8; PAGE_HEADER_SIZE as usize];
+            check!(file.seek(SeekFrom::Start(page_loc(page))));
+            check!(file.read_exact(&mut buf));
+            let (region, index);
+            check!(u64::raw_deserialize(&mut &buf[.. 8]), region);
+            check!(u64::raw_deserialize(&mut &buf[8 ..]), index);
+            // A page whose header never reached the disk was never used for anything else either
+            if region == 0 {
+                free.insert(page);
+                continue
+            }
+            if region > 3 || index >= num_pages {
+                return Err(Error::new(ErrorKind::InvalidData, format!("page {} of {} has a corrupt header", page, name)));
+            }
+            let (region, index) = (region as usize - 1, index as usize);
+            // Pages past the end of their region hold nothing the log won't write again
+            if index as u64 >= ends[region].div_ceil(PAGE_DATA) {
+                free.insert(page);
+                uncleared.insert(page);
+                continue
+            }
+            let region = &mut pages[region];
+            if region.len() <= index { region.resize(index + 1, NONE); }
+            if region[index] != NONE {
+                return Err(Error::new(ErrorKind::InvalidData, format!("page {} of {} is in use twice", page, name)));
+            }
+            region[index] = page;
+        }
+        Ok(Container { file, pages, num_pages, free, uncleared, ends, dirty: false })
+    }
+
+    fn write_header(&mut self) -> Result<(), Error> {
+        let header = ContainerHeader {
+            magic: CONTAINER_MAGIC,
+            version: CONTAINER_VERSION,
+            page_size: PAGE_SIZE,
+            tree_len: self.ends[0],
+            key_len: self.ends[1],
+            val_len: self.ends[2]
+        };
+        let mut buf = vec![];
+        check!(header.raw_serialize(&mut buf));
+        buf.resize(CONTAINER_HEADER_SIZE as usize, 0);
+        check!(self.file.seek(SeekFrom::Start(0)));
+        self.file.write_all(&buf)
+    }
+
+    /// The location in the file of byte pos of region, None if its page was never written to.
+    /// If alloc is set, a page is handed out for it instead.
+    fn locate(&mut self, region: usize, pos: u64, alloc: bool) -> Result<Option<u64>, Error> {
+        let index = (pos / PAGE_DATA) as usize;
+        let offset = pos % PAGE_DATA;
+        if index < self.pages[region].len() && self.pages[region][index] != NONE {
+            return Ok(Some(page_loc(self.pages[region][index]) + PAGE_HEADER_SIZE + offset))
+        }
+        if !alloc { return Ok(None) }
+
+        let page = match self.free.iter().next().cloned() {
+            Some(page) => {
+                self.free.remove(&page);
+                self.uncleared.remove(&page);
+                page
+            },
+            None => {
+                self.num_pages += 1;
+                self.num_pages - 1
+            }
+        };
+        let mut buf = vec![];
+        check!((region as u64 + 1).raw_serialize(&mut buf));
+        check!((index as u64).raw_serialize(&mut buf));
+        check!(self.file.seek(SeekFrom::Start(page_loc(page))));
+        check!(self.file.write_all(&buf));
+        let pages = &mut self.pages[region];
+        if pages.len() <= index { pages.resize(index + 1, NONE); }
+        pages[index] = page;
+        Ok(Some(page_loc(page) + PAGE_HEADER_SIZE + offset))
+    }
+
+    fn read_at(&mut self, region: usize, pos: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            let n = cmp::min((PAGE_DATA - at % PAGE_DATA) as usize, buf.len() - done);
+            let loc;
+            check!(self.locate(region, at, false), loc);
+            match loc {
+                Some(loc) => {
+                    check!(self.file.seek(SeekFrom::Start(loc)));
+                    check!(self.file.read_exact(&mut buf[done .. done + n]));
+                },
+                None => for b in buf[done .. done + n].iter_mut() { *b = 0; }
+            }
+            done += n;
+        }
+        // Pages may hold old bytes past the end of the region
+        let end = self.ends[region];
+        if pos + buf.len() as u64 > end {
+            let from = end.saturating_sub(pos) as usize;
+            for b in buf[from ..].iter_mut() { *b = 0; }
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, region: usize, pos: u64, bytes: &[u8]) -> Result<(), Error> {
+        let mut done = 0;
+        while done < bytes.len() {
+            let at = pos + done as u64;
+            let n = cmp::min((PAGE_DATA - at % PAGE_DATA) as usize, bytes.len() - done);
+            let loc;
+            check!(self.locate(region, at, true), loc);
+            check!(self.file.seek(SeekFrom::Start(loc.unwrap())));
+            check!(self.file.write_all(&bytes[done .. done + n]));
+            done += n;
+        }
+        let end = pos + bytes.len() as u64;
+        if end > self.ends[region] { self.ends[region] = end; }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Moves the end of region back to len, and hands back the pages wholly past it.
+    fn truncate(&mut self, region: usize, len: u64) {
+        self.ends[region] = len;
+        let keep = len.div_ceil(PAGE_DATA) as usize;
+        if self.pages[region].len() > keep {
+            for page in self.pages[region].split_off(keep) {
+                if page == NONE { continue }
+                self.free.insert(page);
+                self.uncleared.insert(page);
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Writes the bytes in [start, end) of region back to the file, along with the headers of
+    /// the pages that start in the range, without waiting for them to reach the disk.
+    fn flush_region(&mut self, region: usize, start: u64, end: u64) -> Result<(), Error> {
+        let mut at = start;
+        while at < end {
+            let n = cmp::min(PAGE_DATA - at % PAGE_DATA, end - at);
+            let loc;
+            check!(self.locate(region, at, false), loc);
+            if let Some(loc) = loc {
+                let from = if at.is_multiple_of(PAGE_DATA) { loc - PAGE_HEADER_SIZE } else { loc };
+                check!(self.file.flush_range(from, loc + n));
+            }
+            at += n;
+        }
+        Ok(())
+    }
+
+    /// Syncs the file in the order PBTree::sync promises, unless nothing has changed since it
+    /// was last synced: the keys and values, and the cleared headers of free pages, then the
+    /// nodes, then the header of the tree and the header of the file.
+    fn sync(&mut self) -> Result<(), Error> {
+        if !self.dirty { return Ok(()) }
+        for page in mem::take(&mut self.uncleared) {
+            check!(self.file.seek(SeekFrom::Start(page_loc(page))));
+            check!(self.file.write_all(&[0; PAGE_HEADER_SIZE as usize]));
+            check!(self.file.flush_range(page_loc(page), page_loc(page) + PAGE_HEADER_SIZE));
+        }
+        let (key_end, val_end) = (self.ends[1], self.ends[2]);
+        check!(self.flush_region(1, 0, key_end));
+        check!(self.flush_region(2, 0, val_end));
+        check!(self.file.sync_flushed());
+        let tree_end = self.ends[0];
+        check!(self.flush_region(0, HEADER_SIZE, tree_end));
+        check!(self.file.sync_flushed());
+        check!(self.write_header());
+        check!(self.file.sync());
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        // The file buffer writes this back when it is dropped, right after
+        if self.dirty {
+            let _ = self.write_header();
+        }
+    }
+}
+
+/// Buffers file with the slab size and number of slabs of options.
+fn buffer(file: File, options: &Options) -> Result<BufFile, Error> {
+    BufFile::with_slab_size(options.slab_size, options.policy.new_policy(options.slabs), file)
+}
+
+/// The location in the file of the start of a page.
+fn page_loc(page: u64) -> u64 {
+    CONTAINER_HEADER_SIZE + page * PAGE_SIZE
+}
+
+/// One of the three regions of a tree kept in a single file, `<path>.pbt`, rather than in a
+/// .tree, .key and .val file. A single file is easier to copy, back up and replace atomically,
+/// and its parts can't drift apart. Once the tree has been synced its write-ahead log is empty,
+/// and the single file is all there is to it.
+///
+/// The three regions of a tree share one file and one file buffer, so a tree in a single file is
+/// created or opened by its type, e.g. `PBTree::<K, V, SingleFile>::open(path)`. They share it
+/// through a lock, so the tree can be sent to another thread like any other, but it can't be
+/// shared with SharedPBTree.
+pub struct SingleFile {
+    container: Arc<Mutex<Container>>,
+    /// 0 for the nodes, 1 for the keys and 2 for the values.
+    region: usize
+}
+
+impl SingleFile {
+    fn container(&self) -> MutexGuard<'_, Container> {
+        // Only the tree the regions belong to uses them, one at a time, so a panic while one
+        // was in use leaves the container no worse off than the tree itself
+        match self.container.lock() {
+            Ok(container) => container,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+}
+
+impl Storage for SingleFile {
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.container().read_at(self.region, pos, buf)
+    }
+
+    fn write_at(&mut self, pos: u64, bytes: &[u8]) -> Result<(), Error> {
+        self.container().write_at(self.region, pos, bytes)
+    }
+
+    fn len(&self) -> u64 {
+        self.container().ends[self.region]
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        self.container().truncate(self.region, len);
+        Ok(())
+    }
+
+    /// Syncs the whole file, which holds the other two regions too, in the order PBTree::sync
+    /// promises, so syncing them as well right after costs nothing.
+    fn sync(&mut self) -> Result<(), Error> {
+        self.container().sync()
+    }
+
+    /// The same as sync, since the regions are not laid out in order in the file.
+    fn sync_range(&mut self, _start: u64, _end: u64) -> Result<(), Error> {
+        self.container().sync()
+    }
+
+    fn pin_writes(&mut self, pin: bool) -> Result<(), Error> {
+        self.container().file.pin_writes(pin)
+    }
+}
+
+impl FileStorage for SingleFile {
+    const EXTENSIONS: &'static [&'static str] = &[".pbt"];
+
+    fn open_files(path: &str, create: bool, options: &Options) -> Result<(Self, Self, Self), Error> {
+        let name = path.to_string() + ".pbt";
+        let file = match OpenOptions::new().read(true).write(true).truncate(create).create(create).open(&name) {
+            Ok(file) => file,
+            Err(e) => return Err(Error::new(e.kind(), format!("could not open {}: {}", name, e)))
+        };
+        let container;
+        if create {
+            check!(Container::create(file, options), container);
+        } else {
+            check!(Container::open(file, &name, options), container);
+        }
+        let container = Arc::new(Mutex::new(container));
+        Ok((SingleFile { container: container.clone(), region: 0 },
+            SingleFile { container: container.clone(), region: 1 },
+            SingleFile { container, region: 2 }))
+    }
+}
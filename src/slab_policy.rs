@@ -0,0 +1,342 @@
+use std::cmp;
+use std::collections::{ BTreeMap, BTreeSet, HashMap };
+use std::hash::Hash;
+
+/// Decides which slab a BufFile evicts when it needs room for another. Slabs are identified by
+/// their slot, an index into the BufFile's slabs. Policies must be Send so that trees can be
+/// shared between threads.
+pub trait SlabPolicy: Send {
+    /// The number of slabs the BufFile may keep in memory.
+    fn capacity(&self) -> usize;
+
+    /// The slab beginning at file index `start` was loaded into `slot`.
+    fn loaded(&mut self, slot: usize, start: u64);
+
+    /// The slab in `slot` was read from or written to again after being loaded.
+    fn accessed(&mut self, slot: usize);
+
+    /// The slab in `slot` was dropped without anything replacing it.
+    fn removed(&mut self, slot: usize);
+
+    /// Chooses the slot whose slab is replaced by the slab beginning at file index `incoming`,
+    /// skipping slots for which `pinned` returns true, and forgets the slab that was in it.
+    /// Returns None if every slot is pinned.
+    fn evict(&mut self, incoming: u64, pinned: &dyn Fn(usize) -> bool) -> Option<usize>;
+}
+
+/// The policies a tree can open its BufFiles with, as chosen by `Options::policy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyKind {
+    Lfu,
+    Lru,
+    Clock,
+    Arc
+}
+
+impl PolicyKind {
+    /// A new policy of this kind for a BufFile that keeps capacity slabs.
+    pub fn new_policy(self, capacity: usize) -> Box<dyn SlabPolicy> {
+        match self {
+            PolicyKind::Lfu => Box::new(LfuPolicy::new(capacity)),
+            PolicyKind::Lru => Box::new(LruPolicy::new(capacity)),
+            PolicyKind::Clock => Box::new(ClockPolicy::new(capacity)),
+            PolicyKind::Arc => Box::new(ArcPolicy::new(capacity))
+        }
+    }
+}
+
+/// Every use count is halved once there have been this many uses per slab the policy keeps
+/// since the last time, so a slab that was used heavily once and not since is evicted in time.
+pub const LFU_AGING: u64 = 16;
+
+/// Evicts the least frequently used slab. Use counts decay, as LFU_AGING describes.
+pub struct LfuPolicy {
+    capacity: usize,
+    uses: HashMap<usize, u64>,
+    /// (uses, slot) for every slot, so the least used slab comes first.
+    order: BTreeSet<(u64, usize)>,
+    /// The number of uses since the counts were last halved.
+    since_aging: u64
+}
+
+impl LfuPolicy {
+    pub fn new(capacity: usize) -> Self {
+        LfuPolicy { capacity, uses: HashMap::new(), order: BTreeSet::new(), since_aging: 0 }
+    }
+
+    /// Counts a use, halving every use count if enough have been counted.
+    fn count_use(&mut self) {
+        self.since_aging += 1;
+        if self.since_aging < LFU_AGING * self.capacity as u64 { return }
+        self.since_aging = 0;
+        self.order.clear();
+        for (&slot, uses) in self.uses.iter_mut() {
+            *uses = cmp::max(*uses / 2, 1);
+            self.order.insert((*uses, slot));
+        }
+    }
+}
+
+impl SlabPolicy for LfuPolicy {
+    fn capacity(&self) -> usize { self.capacity }
+
+    fn loaded(&mut self, slot: usize, _start: u64) {
+        self.removed(slot);
+        self.uses.insert(slot, 1);
+        self.order.insert((1, slot));
+        self.count_use();
+    }
+
+    fn accessed(&mut self, slot: usize) {
+        if let Some(uses) = self.uses.get_mut(&slot) {
+            self.order.remove(&(*uses, slot));
+            *uses += 1;
+            self.order.insert((*uses, slot));
+        }
+        self.count_use();
+    }
+
+    fn removed(&mut self, slot: usize) {
+        if let Some(uses) = self.uses.remove(&slot) {
+            self.order.remove(&(uses, slot));
+        }
+    }
+
+    fn evict(&mut self, _incoming: u64, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let victim = self.order.iter().map(|&(_, slot)| slot).find(|&slot| !pinned(slot));
+        if let Some(slot) = victim { self.removed(slot); }
+        victim
+    }
+}
+
+/// A set of items ordered from least to most recently used.
+struct LruList<T> {
+    order: BTreeMap<u64, T>,
+    stamps: HashMap<T, u64>,
+    clock: u64
+}
+
+impl<T: Copy + Eq + Hash> LruList<T> {
+    fn new() -> Self {
+        LruList { order: BTreeMap::new(), stamps: HashMap::new(), clock: 0 }
+    }
+
+    fn len(&self) -> usize { self.stamps.len() }
+
+    fn contains(&self, x: &T) -> bool { self.stamps.contains_key(x) }
+
+    /// Adds x as the most recently used item, moving it if it is already present.
+    fn push(&mut self, x: T) {
+        self.remove(&x);
+        self.clock += 1;
+        self.order.insert(self.clock, x);
+        self.stamps.insert(x, self.clock);
+    }
+
+    fn remove(&mut self, x: &T) -> bool {
+        match self.stamps.remove(x) {
+            Some(stamp) => { self.order.remove(&stamp); true },
+            None => false
+        }
+    }
+
+    fn pop_lru(&mut self) -> Option<T> {
+        let lru = self.order.values().next().cloned();
+        if let Some(x) = lru { self.remove(&x); }
+        lru
+    }
+
+    /// The least recently used item that matches f.
+    fn lru_matching<F: Fn(T) -> bool>(&self, f: F) -> Option<T> {
+        self.order.values().cloned().find(|x| f(*x))
+    }
+}
+
+/// Evicts the least recently used slab.
+pub struct LruPolicy {
+    capacity: usize,
+    list: LruList<usize>
+}
+
+impl LruPolicy {
+    pub fn new(capacity: usize) -> Self {
+        LruPolicy { capacity, list: LruList::new() }
+    }
+}
+
+impl SlabPolicy for LruPolicy {
+    fn capacity(&self) -> usize { self.capacity }
+
+    fn loaded(&mut self, slot: usize, _start: u64) { self.list.push(slot); }
+
+    fn accessed(&mut self, slot: usize) { self.list.push(slot); }
+
+    fn removed(&mut self, slot: usize) { self.list.remove(&slot); }
+
+    fn evict(&mut self, _incoming: u64, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let victim = self.list.lru_matching(|slot| !pinned(slot));
+        if let Some(slot) = victim { self.list.remove(&slot); }
+        victim
+    }
+}
+
+/// Approximates LRU with a reference bit per slot and a hand that sweeps over the slots,
+/// evicting the first slab that hasn't been accessed since the hand last passed it.
+pub struct ClockPolicy {
+    capacity: usize,
+    present: Vec<bool>,
+    referenced: Vec<bool>,
+    hand: usize
+}
+
+impl ClockPolicy {
+    pub fn new(capacity: usize) -> Self {
+        ClockPolicy { capacity, present: vec![], referenced: vec![], hand: 0 }
+    }
+}
+
+impl SlabPolicy for ClockPolicy {
+    fn capacity(&self) -> usize { self.capacity }
+
+    fn loaded(&mut self, slot: usize, _start: u64) {
+        if slot >= self.present.len() {
+            self.present.resize(slot + 1, false);
+            self.referenced.resize(slot + 1, false);
+        }
+        self.present[slot] = true;
+        self.referenced[slot] = false;
+    }
+
+    fn accessed(&mut self, slot: usize) {
+        if slot < self.referenced.len() { self.referenced[slot] = true; }
+    }
+
+    fn removed(&mut self, slot: usize) {
+        if slot < self.present.len() { self.present[slot] = false; }
+    }
+
+    fn evict(&mut self, _incoming: u64, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let n = self.present.len();
+        // Two sweeps clear every reference bit, so anything not found by then is pinned
+        for _ in 0..2 * n {
+            let slot = self.hand;
+            self.hand = (self.hand + 1) % n;
+            if !self.present[slot] || pinned(slot) { continue }
+            if self.referenced[slot] {
+                self.referenced[slot] = false;
+                continue
+            }
+            self.present[slot] = false;
+            return Some(slot)
+        }
+        None
+    }
+}
+
+/// Adaptive Replacement Cache (Megiddo and Modha). Slabs seen once live in t1 and slabs seen
+/// again in t2; b1 and b2 remember the starts of slabs recently evicted from each. A miss on a
+/// remembered slab shifts the target size p of t1 towards whichever list would have kept it.
+pub struct ArcPolicy {
+    capacity: usize,
+    p: usize,
+    t1: LruList<usize>,
+    t2: LruList<usize>,
+    b1: LruList<u64>,
+    b2: LruList<u64>,
+    /// The start of the slab in each slot.
+    starts: HashMap<usize, u64>
+}
+
+impl ArcPolicy {
+    pub fn new(capacity: usize) -> Self {
+        ArcPolicy {
+            capacity,
+            p: 0,
+            t1: LruList::new(),
+            t2: LruList::new(),
+            b1: LruList::new(),
+            b2: LruList::new(),
+            starts: HashMap::new()
+        }
+    }
+
+    /// Evicts the least recently used unpinned slab from t1 or t2, depending on p, and
+    /// remembers it in the matching ghost list.
+    fn replace(&mut self, incoming: u64, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let prefer_t1 = self.t1.len() > 0
+            && (self.t1.len() > self.p || (self.b2.contains(&incoming) && self.t1.len() == self.p));
+        let (first_t1, first) = if prefer_t1 {
+            (true, self.t1.lru_matching(|slot| !pinned(slot)))
+        } else {
+            (false, self.t2.lru_matching(|slot| !pinned(slot)))
+        };
+        let (from_t1, victim) = match first {
+            Some(slot) => (first_t1, slot),
+            None => {
+                let other = if first_t1 { self.t2.lru_matching(|slot| !pinned(slot)) }
+                            else { self.t1.lru_matching(|slot| !pinned(slot)) };
+                (!first_t1, other?)
+            }
+        };
+        let start = self.starts.remove(&victim).unwrap();
+        if from_t1 {
+            self.t1.remove(&victim);
+            self.b1.push(start);
+        } else {
+            self.t2.remove(&victim);
+            self.b2.push(start);
+        }
+        Some(victim)
+    }
+}
+
+impl SlabPolicy for ArcPolicy {
+    fn capacity(&self) -> usize { self.capacity }
+
+    fn loaded(&mut self, slot: usize, start: u64) {
+        self.starts.insert(slot, start);
+        // A slab that was evicted recently has now been seen more than once
+        if self.b1.remove(&start) || self.b2.remove(&start) {
+            self.t2.push(slot);
+        } else {
+            self.t1.push(slot);
+        }
+    }
+
+    fn accessed(&mut self, slot: usize) {
+        if self.t1.remove(&slot) || self.t2.contains(&slot) {
+            self.t2.push(slot);
+        }
+    }
+
+    fn removed(&mut self, slot: usize) {
+        self.t1.remove(&slot);
+        self.t2.remove(&slot);
+        self.starts.remove(&slot);
+    }
+
+    fn evict(&mut self, incoming: u64, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let c = self.capacity;
+        if self.b1.contains(&incoming) {
+            let delta = cmp::max(self.b2.len() / self.b1.len(), 1);
+            self.p = cmp::min(c, self.p + delta);
+        } else if self.b2.contains(&incoming) {
+            let delta = cmp::max(self.b1.len() / self.b2.len(), 1);
+            self.p = self.p.saturating_sub(delta);
+        } else if self.t1.len() + self.b1.len() >= c {
+            if self.t1.len() < c {
+                self.b1.pop_lru();
+            } else {
+                // t1 alone fills the cache, so its LRU slab is dropped without being remembered
+                let victim = self.t1.lru_matching(|slot| !pinned(slot));
+                if let Some(slot) = victim {
+                    self.removed(slot);
+                    return Some(slot)
+                }
+            }
+        } else if self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() >= 2 * c {
+            self.b2.pop_lru();
+        }
+        self.replace(incoming, pinned)
+    }
+}
@@ -0,0 +1,279 @@
+use std::cmp;
+use std::fs::{ File, OpenOptions };
+use std::io::{ Error, Read, Seek, SeekFrom, Write };
+#[allow(unused_imports)]
+use raw_serde::*;
+use btree::Options;
+use file_buffer::{ BufFile, FileReader };
+
+/// Somewhere the bytes of one of a PBTree's files can be kept: the nodes, the keys or the
+/// values. Everything is read and written by position, and the storage grows when it is
+/// written to past its end.
+pub trait Storage {
+    /// Fills buf with the bytes starting at pos. Bytes past the end read as zeros.
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes bytes at pos, moving the end if they go past it.
+    fn write_at(&mut self, pos: u64, bytes: &[u8]) -> Result<(), Error>;
+
+    /// The number of bytes in the storage.
+    fn len(&self) -> u64;
+
+    /// Whether the storage holds no bytes.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Moves the end of the storage back to len, e.g. to roll back an operation that appended.
+    /// The bytes past the new end need not be cleared.
+    fn truncate(&mut self, len: u64) -> Result<(), Error>;
+
+    /// Waits for everything written to reach the disk, if there is one.
+    fn sync(&mut self) -> Result<(), Error>;
+
+    /// Waits for the bytes in [start, end) to reach the disk, so parts of the storage can be
+    /// made durable in a chosen order. Syncs everything unless the storage can do better.
+    fn sync_range(&mut self, _start: u64, _end: u64) -> Result<(), Error> {
+        self.sync()
+    }
+
+    /// Starts or stops holding writes back from the disk. A tree pins writes for the length of
+    /// each operation, so nothing it writes reaches the disk before it has been logged. Storage
+    /// that is not backed by a disk has nothing to hold back.
+    fn pin_writes(&mut self, _pin: bool) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Storage kept in files, which trees created or opened at a path keep their nodes, keys and
+/// values in. Which kind a tree uses is chosen by its type, e.g.
+/// `PBTree::<K, V, MmapFile>::open(path)`.
+pub trait FileStorage: Storage + Sized {
+    /// The extensions of the files a tree is kept in, which are appended to its path.
+    const EXTENSIONS: &'static [&'static str];
+
+    /// Opens the files of the tree at path and returns the storage for its nodes, keys and
+    /// values. If create is set the files are created, or truncated if they exist. Storage that
+    /// buffers its files takes the slab size and number of slabs from options.
+    fn open_files(path: &str, create: bool, options: &Options) -> Result<(Self, Self, Self), Error>;
+}
+
+/// Storage that many threads can read from at once, through readers of their own, while a
+/// SharedPBTree writes to it.
+pub trait SharedStorage: Storage + Send {
+    type Reader: SharedRead;
+
+    /// A reader that sees what the storage holds now, and what is published to it later.
+    fn reader(&self) -> Result<Self::Reader, Error>;
+
+    /// Makes everything written so far visible to reader, without waiting for it to reach the
+    /// disk. Nothing is written where reader can see it between calls.
+    fn publish(&mut self, reader: &mut Self::Reader) -> Result<(), Error>;
+}
+
+/// Reads storage through a shared reference, so many threads can read at once.
+pub trait SharedRead: Send + Sync {
+    /// Fills buf with the bytes starting at pos. Bytes past the end read as zeros.
+    fn read_shared(&self, pos: u64, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// The number of bytes that can be read.
+    fn len(&self) -> u64;
+
+    /// Whether there are no bytes to read.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Opens the .tree, .key and .val files of the tree at path, as for FileStorage::open_files, and
+/// wraps each with wrap. Errors name the file that could not be opened.
+pub fn open_three_files<S, F>(path: &str, create: bool, wrap: F) -> Result<(S, S, S), Error>
+    where F: Fn(File) -> Result<S, Error> {
+    let mut files = vec![];
+    for ext in [".tree", ".key", ".val"].iter() {
+        let name = path.to_string() + ext;
+        let file = match OpenOptions::new().read(true).write(true).truncate(create).create(create).open(&name) {
+            Ok(file) => file,
+            Err(e) => return Err(Error::new(e.kind(), format!("could not open {}: {}", name, e)))
+        };
+        let storage;
+        check!(wrap(file), storage);
+        files.push(storage);
+    }
+    let mut files = files.into_iter();
+    Ok((files.next().unwrap(), files.next().unwrap(), files.next().unwrap()))
+}
+
+impl Storage for BufFile {
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), Error> {
+        // Only the bytes before the end are read. The slabs and the file may still hold bytes
+        // past it, e.g. after a truncate, and those must read as zeros.
+        let n = cmp::min(self.end.saturating_sub(pos), buf.len() as u64) as usize;
+        if n > 0 {
+            check!(self.seek(SeekFrom::Start(pos)));
+            check!(self.read_exact(&mut buf[.. n]));
+        }
+        for b in buf[n ..].iter_mut() { *b = 0; }
+        Ok(())
+    }
+
+    fn write_at(&mut self, pos: u64, bytes: &[u8]) -> Result<(), Error> {
+        check!(self.seek(SeekFrom::Start(pos)));
+        self.write_all(bytes)
+    }
+
+    fn len(&self) -> u64 {
+        self.end
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        BufFile::truncate(self, len)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        BufFile::sync(self)
+    }
+
+    fn sync_range(&mut self, start: u64, end: u64) -> Result<(), Error> {
+        BufFile::sync_range(self, start, end)
+    }
+
+    fn pin_writes(&mut self, pin: bool) -> Result<(), Error> {
+        BufFile::pin_writes(self, pin)
+    }
+}
+
+impl FileStorage for BufFile {
+    const EXTENSIONS: &'static [&'static str] = &[".tree", ".key", ".val"];
+
+    fn open_files(path: &str, create: bool, options: &Options) -> Result<(Self, Self, Self), Error> {
+        open_three_files(path, create, |file| BufFile::with_slab_size(options.slab_size, options.policy.new_policy(options.slabs), file))
+    }
+}
+
+/// Shared reads go to the file itself, through a handle of their own, so publishing writes back
+/// every slab.
+impl SharedStorage for BufFile {
+    type Reader = FileReader;
+
+    fn reader(&self) -> Result<FileReader, Error> {
+        FileReader::new(self)
+    }
+
+    fn publish(&mut self, reader: &mut FileReader) -> Result<(), Error> {
+        check!(self.flush());
+        reader.end = self.end;
+        Ok(())
+    }
+}
+
+/// Storage held entirely in memory, which lasts only as long as the Vec does.
+impl Storage for Vec<u8> {
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let start = cmp::min(pos, Vec::len(self) as u64) as usize;
+        let end = cmp::min(start + buf.len(), Vec::len(self));
+        let n = end - start;
+        buf[.. n].copy_from_slice(&self[start .. end]);
+        for b in buf[n ..].iter_mut() { *b = 0; }
+        Ok(())
+    }
+
+    fn write_at(&mut self, pos: u64, bytes: &[u8]) -> Result<(), Error> {
+        let end = pos as usize + bytes.len();
+        if end > Vec::len(self) { self.resize(end, 0); }
+        self[pos as usize .. end].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        Vec::len(self) as u64
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        Vec::truncate(self, len as usize);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Searches read a copy of the Vec, which publishing brings up to date. That copies all of it,
+/// so sharing a tree in memory only suits small trees.
+impl SharedStorage for Vec<u8> {
+    type Reader = Vec<u8>;
+
+    fn reader(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.clone())
+    }
+
+    fn publish(&mut self, reader: &mut Vec<u8>) -> Result<(), Error> {
+        reader.clear();
+        reader.extend_from_slice(self);
+        Ok(())
+    }
+}
+
+impl SharedRead for Vec<u8> {
+    fn read_shared(&self, pos: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let start = cmp::min(pos, Vec::len(self) as u64) as usize;
+        let end = cmp::min(start + buf.len(), Vec::len(self));
+        let n = end - start;
+        buf[.. n].copy_from_slice(&self[start .. end]);
+        for b in buf[n ..].iter_mut() { *b = 0; }
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        Vec::len(self) as u64
+    }
+}
+
+/// Reads a Storage front to back from a position, so a value can be deserialized from it
+/// without knowing its size in advance.
+pub struct Reader<'a, S: 'a + ?Sized> {
+    storage: &'a mut S,
+    /// The position of the next byte to be read.
+    pub pos: u64
+}
+
+impl<'a, S: Storage + ?Sized> Reader<'a, S> {
+    pub fn new(storage: &'a mut S, pos: u64) -> Self {
+        Reader { storage, pos }
+    }
+}
+
+/// Reads stop at the end of the storage, so a record that claims to run past it fails with
+/// ErrorKind::UnexpectedEof rather than reading zeros.
+impl<'a, S: Storage + ?Sized> Read for Reader<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = cmp::min(self.storage.len().saturating_sub(self.pos), buf.len() as u64) as usize;
+        check!(self.storage.read_at(self.pos, &mut buf[.. n]));
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Like Reader, but for reading through a shared reference.
+pub struct SharedReader<'a, S: 'a + ?Sized> {
+    storage: &'a S,
+    /// The position of the next byte to be read.
+    pub pos: u64
+}
+
+impl<'a, S: SharedRead + ?Sized> SharedReader<'a, S> {
+    pub fn new(storage: &'a S, pos: u64) -> Self {
+        SharedReader { storage, pos }
+    }
+}
+
+/// Reads stop at the end, as for Reader.
+impl<'a, S: SharedRead + ?Sized> Read for SharedReader<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = cmp::min(self.storage.len().saturating_sub(self.pos), buf.len() as u64) as usize;
+        check!(self.storage.read_shared(self.pos, &mut buf[.. n]));
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
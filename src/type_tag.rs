@@ -0,0 +1,93 @@
+use std::mem;
+use std::collections::{ BTreeMap, BTreeSet, VecDeque };
+
+/// Types that can be the keys or values of a tree. The header of a tree records a hash of the
+/// tags of its key and value types, so that opening it with other types fails rather than
+/// misreading the files.
+///
+/// The tag is written to disk, so it must not change once trees have been created with the type:
+/// a tree whose recorded tag no longer matches can't be opened. Renaming or moving a type is
+/// harmless as long as its tag stays the same, and two types should only share a tag if each
+/// can read what the other writes.
+///
+/// There are three ways to implement it for a type of your own:
+///
+/// - `type_tag!(Point);` tags it with its name as written, here "Point".
+/// - `impl TypeTag for Point {}` tags it with `std::any::type_name`, which includes the module
+///   path and is not promised to stay the same between compiler versions.
+/// - A type_tag of "" opts out: its fingerprint is 0, and trees are opened with it, or opened
+///   with any type if they were created with it, without checking.
+///
+/// Keys and values have had to implement TypeTag since format version 4, which added the
+/// fingerprints to the header. Code using types of its own needs one of the above to build, and
+/// trees written by earlier versions can't be opened.
+pub trait TypeTag {
+    fn type_tag() -> String {
+        ::std::any::type_name::<Self>().to_string()
+    }
+}
+
+/// Implements TypeTag for each of the types given, tagging it with its name as written.
+#[macro_export]
+macro_rules! type_tag {
+    ($($t:ty),*) => {
+        $(impl $crate::TypeTag for $t { fn type_tag() -> String { stringify!($t).to_string() } })*
+    }
+}
+
+type_tag!(bool, u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64, String);
+
+/// usize and isize are written with as many bytes as they have, so they are tagged by their
+/// width: a usize tree on a 64 bit target is a u64 tree, and can't be opened on a 32 bit one.
+impl TypeTag for usize {
+    fn type_tag() -> String { format!("u{}", mem::size_of::<usize>() * 8) }
+}
+
+impl TypeTag for isize {
+    fn type_tag() -> String { format!("i{}", mem::size_of::<isize>() * 8) }
+}
+
+macro_rules! array_type_tag {
+    ($($n:expr),*) => {
+        $(impl<T: TypeTag> TypeTag for [T; $n] {
+            fn type_tag() -> String { format!("[{}; {}]", T::type_tag(), $n) }
+        })*
+    }
+}
+
+array_type_tag!(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16);
+array_type_tag!(17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32);
+
+impl<T: TypeTag> TypeTag for Vec<T> {
+    fn type_tag() -> String { format!("Vec<{}>", T::type_tag()) }
+}
+
+impl<T: TypeTag> TypeTag for VecDeque<T> {
+    fn type_tag() -> String { format!("VecDeque<{}>", T::type_tag()) }
+}
+
+impl<T: TypeTag> TypeTag for BTreeSet<T> {
+    fn type_tag() -> String { format!("BTreeSet<{}>", T::type_tag()) }
+}
+
+impl<K: TypeTag, V: TypeTag> TypeTag for BTreeMap<K, V> {
+    fn type_tag() -> String { format!("BTreeMap<{}, {}>", K::type_tag(), V::type_tag()) }
+}
+
+impl<T: TypeTag> TypeTag for Option<T> {
+    fn type_tag() -> String { format!("Option<{}>", T::type_tag()) }
+}
+
+impl<A: TypeTag, B: TypeTag> TypeTag for (A, B) {
+    fn type_tag() -> String { format!("({}, {})", A::type_tag(), B::type_tag()) }
+}
+
+impl<A: TypeTag, B: TypeTag, C: TypeTag> TypeTag for (A, B, C) {
+    fn type_tag() -> String { format!("({}, {}, {})", A::type_tag(), B::type_tag(), C::type_tag()) }
+}
+
+impl<A: TypeTag, B: TypeTag, C: TypeTag, D: TypeTag> TypeTag for (A, B, C, D) {
+    fn type_tag() -> String {
+        format!("({}, {}, {}, {})", A::type_tag(), B::type_tag(), C::type_tag(), D::type_tag())
+    }
+}
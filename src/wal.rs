@@ -0,0 +1,163 @@
+use std::fs::File;
+use std::io::{ Error, ErrorKind, Read, Seek, SeekFrom, Write };
+#[allow(unused_imports)]
+use raw_serde::*;
+use storage::Storage;
+
+/// Identifies the treefile in a log record.
+pub const TREE_FILE: u8 = 0;
+/// Identifies the keyfile in a log record.
+pub const KEY_FILE: u8 = 1;
+/// Identifies the valfile in a log record.
+pub const VAL_FILE: u8 = 2;
+
+/// Once the log grows past this many bytes, the tree checkpoints at the next commit.
+pub const CHECKPOINT_SIZE: u64 = 32 * 1024 * 1024;
+
+/// A redo log for the three files of a PBTree.
+///
+/// Every write the tree makes during an operation is recorded as the file, offset and bytes
+/// written. When the operation completes the records are appended to the log as a single
+/// transaction: its length, a checksum of its records, then the records. The log is synced
+/// before the commit returns, so a committed operation can always be replayed. A transaction
+/// that was only partly written when the process died fails its checksum, and it and anything
+/// after it is ignored.
+///
+/// A tree whose storage is not a set of files at a path runs with a disabled log, which records
+/// nothing.
+pub struct Wal {
+    /// The log file, None if the log is disabled.
+    file: Option<File>,
+    /// Encoded records of the transaction in progress.
+    pending: Vec<u8>,
+    /// Length of the log on disk.
+    pub len: u64
+}
+
+impl Wal {
+    pub fn new(mut file: File) -> Result<Wal, Error> {
+        let len;
+        check!(file.seek(SeekFrom::End(0)), len);
+        Ok(Wal {
+            file: Some(file),
+            pending: vec![],
+            len
+        })
+    }
+
+    /// A log that records nothing, and so has nothing to replay.
+    pub fn disabled() -> Wal {
+        Wal {
+            file: None,
+            pending: vec![],
+            len: 0
+        }
+    }
+
+    /// Records that bytes were written to the given file at pos.
+    pub fn log(&mut self, file_id: u8, pos: u64, bytes: &[u8]) {
+        if self.file.is_none() { return }
+        // Writing to a Vec can't fail
+        let _ = file_id.raw_serialize(&mut self.pending);
+        let _ = pos.raw_serialize(&mut self.pending);
+        let _ = (bytes.len() as u64).raw_serialize(&mut self.pending);
+        self.pending.extend_from_slice(bytes);
+    }
+
+    /// Appends the records of the transaction in progress to the log and syncs it.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        if self.pending.is_empty() { return Ok(()) }
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return Ok(())
+        };
+        let mut txn = Vec::with_capacity(16 + self.pending.len());
+        let _ = (self.pending.len() as u64).raw_serialize(&mut txn);
+        let _ = checksum(&self.pending).raw_serialize(&mut txn);
+        txn.extend_from_slice(&self.pending);
+
+        check!(file.seek(SeekFrom::Start(self.len)));
+        let res = match file.write_all(&txn) {
+            Ok(()) => file.sync_data(),
+            Err(e) => Err(e)
+        };
+        if let Err(e) = res {
+            // The transaction is about to be rolled back, so it must not be replayed either
+            let _ = file.set_len(self.len);
+            return Err(e)
+        }
+        self.len += txn.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Throws away the records of the transaction in progress.
+    pub fn abort(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Applies every committed transaction in the log to the given files, which must be indexed
+    /// by their file ids, then syncs them and empties the log. Returns the number of
+    /// transactions replayed.
+    pub fn replay<S: Storage>(&mut self, files: &mut [S]) -> Result<u64, Error> {
+        let mut log = vec![];
+        if let Some(ref mut file) = self.file {
+            check!(file.seek(SeekFrom::Start(0)));
+            check!(file.read_to_end(&mut log));
+        }
+
+        let mut txns = 0;
+        let mut rest = &log[..];
+        while rest.len() >= 16 {
+            let len;
+            let sum;
+            check!(u64::raw_deserialize(&mut rest), len);
+            check!(u64::raw_deserialize(&mut rest), sum);
+            if len > rest.len() as u64 || checksum(&rest[..len as usize]) != sum { break }
+
+            let (mut records, tail) = rest.split_at(len as usize);
+            rest = tail;
+            while !records.is_empty() {
+                let file_id;
+                let pos;
+                let n;
+                check!(u8::raw_deserialize(&mut records), file_id);
+                check!(u64::raw_deserialize(&mut records), pos);
+                check!(u64::raw_deserialize(&mut records), n);
+                if file_id as usize >= files.len() || n > records.len() as u64 {
+                    return Err(Error::new(ErrorKind::InvalidData, "write-ahead log contains a malformed record"))
+                }
+                let (bytes, tail) = records.split_at(n as usize);
+                records = tail;
+                check!(files[file_id as usize].write_at(pos, bytes));
+            }
+            txns += 1;
+        }
+
+        for file in files.iter_mut() {
+            check!(file.sync());
+        }
+        check!(self.truncate());
+        Ok(txns)
+    }
+
+    /// Empties the log. Only safe once everything it records has reached the disk.
+    pub fn truncate(&mut self) -> Result<(), Error> {
+        if let Some(ref mut file) = self.file {
+            check!(file.set_len(0));
+            check!(file.sync_data());
+        }
+        self.len = 0;
+        Ok(())
+    }
+}
+
+/// 64 bit FNV-1a hash, used to detect torn transactions and to fingerprint types.
+pub fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}